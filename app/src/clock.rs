@@ -0,0 +1,37 @@
+use std::time::{Duration, Instant, SystemTime};
+
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::time::sleep;
+
+use rkvm2_proto::Message;
+
+/// Where the [App](crate::App) gets the time
+pub trait Clock: Send {
+    /// For timeouts and expiry
+    fn now(&self) -> Instant;
+    /// For timestamps other nodes read
+    fn system_time(&self) -> SystemTime;
+    /// Send the message once the delay has gone by on this clock
+    fn send_after(&self, delay: Duration, sender: UnboundedSender<Message>, message: Message);
+}
+
+/// The real time.  Goes by tokio's clock so paused test time counts.
+#[derive(Debug, Default)]
+pub struct SystemClock;
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        tokio::time::Instant::now().into_std()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn send_after(&self, delay: Duration, sender: UnboundedSender<Message>, message: Message) {
+        tokio::spawn(async move {
+            sleep(delay).await;
+            // nobody to wake if the app is gone
+            let _ = sender.send(message);
+        });
+    }
+}
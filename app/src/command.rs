@@ -0,0 +1,30 @@
+use std::process::Command;
+use std::thread;
+
+/// Run a shell command in the background.  Output goes wherever ours does.
+pub(crate) fn run(command: &str) {
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    log::info!("Running {}", command);
+    match shell.arg(command).spawn() {
+        Ok(mut child) => {
+            // reap it so it doesn't hang around as a zombie
+            let command = command.to_string();
+            thread::spawn(move || match child.wait() {
+                Ok(status) if !status.success() => log::warn!("{} failed: {}", command, status),
+                Ok(_) => {}
+                Err(e) => log::warn!("Failed to wait for {}: {}", command, e),
+            });
+        }
+        Err(e) => {
+            log::warn!("Failed to run {}: {}", command, e);
+        }
+    }
+}
@@ -1,7 +1,7 @@
 use std::fmt::Debug;
 use std::io;
 use std::io::Error;
-use std::time::{SystemTime, Duration};
+use std::time::Duration;
 
 use async_trait::async_trait;
 use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
@@ -32,9 +32,9 @@ pub trait Connector: Send + Sync + Debug {
     async fn connect(&self) -> io::Result<(Self::SinkType, Self::StreamType)>;
 }
 
-pub(crate) struct Connection;
+pub struct Connection;
 impl Connection {
-    pub(crate) fn open<T: Connector + 'static>(
+    pub fn open<T: Connector + 'static>(
         connector: T,
         sender: UnboundedSender<Message>,
     ) -> UnboundedSender<Message> {
@@ -49,7 +49,6 @@ impl Connection {
                                 maybe_msg = stream.next() => {
                                     match maybe_msg {
                                         Some(Ok(message)) => {
-                                            log::trace!("{:?}", message.elapsed_time(SystemTime::now()));
                                             if let Err(e) = sender.send(message) {
                                                 log::warn!("Failed to read message {}", e);
                                                 break;
@@ -80,6 +79,6 @@ impl Connection {
             }
         });
 
-        return ret_sender;
+        ret_sender
     }
 }
@@ -0,0 +1,28 @@
+use rkvm2_config::{NotificationEvent, Urgency};
+
+/// The desktop clipboard, shared with the other nodes when the keyboard moves
+pub trait Clipboard: Send {
+    fn get_text(&mut self) -> Result<String, String>;
+    fn set_text(&mut self, text: String) -> Result<(), String>;
+}
+
+/// Shows the user what happened, like who has the conn
+pub trait Notifier: Send {
+    fn notify(&mut self, notification: Notification);
+}
+
+/// A notification, ready to show
+#[derive(Clone, Debug, PartialEq)]
+pub struct Notification {
+    pub event: NotificationEvent,
+    pub summary: String,
+    pub body: String,
+    /// None to leave it to the desktop
+    pub urgency: Option<Urgency>,
+    /// Milliseconds to show it for, 0 to keep it until it's dismissed, or None to leave it to the desktop
+    pub timeout: Option<u32>,
+    /// Empty for none
+    pub icon: String,
+    /// True to replace the last notification that did, so they don't pile up
+    pub replace: bool,
+}
@@ -0,0 +1,141 @@
+use rkvm2_proto::Screen;
+
+use crate::Node;
+
+/// Which way to go from a node's screen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Direction {
+    Left,
+    Right,
+    Up,
+    Down,
+}
+
+impl Direction {
+    fn opposite(self) -> Self {
+        match self {
+            Direction::Left => Direction::Right,
+            Direction::Right => Direction::Left,
+            Direction::Up => Direction::Down,
+            Direction::Down => Direction::Up,
+        }
+    }
+
+    /// The neighbour the screen declares in this direction
+    fn of(self, screen: &Screen) -> &str {
+        match self {
+            Direction::Left => &screen.left,
+            Direction::Right => &screen.right,
+            Direction::Up => &screen.above,
+            Direction::Down => &screen.below,
+        }
+    }
+}
+
+/// Find the node next to `from` in the given direction.  Either node can declare that they're
+/// neighbours so you don't need to repeat yourself on every node.
+pub(crate) fn neighbour(nodes: &[Node], from: usize, direction: Direction) -> Option<usize> {
+    let node = nodes.get(from)?;
+    if let Some(screen) = &node.screen {
+        let name = direction.of(screen);
+        if !name.is_empty() {
+            if let Some(index) = nodes.iter().position(|n| n.is_called(name)) {
+                return Some(index);
+            }
+        }
+    }
+    nodes.iter().position(|n| {
+        n.screen.as_ref()
+            .is_some_and(|screen| node.is_called(direction.opposite().of(screen)))
+    })
+}
+
+/// Like [neighbour], but if there's nothing that way and `wrap_around` is set, go to the node
+/// furthest the other way instead.
+pub(crate) fn next_in_direction(nodes: &[Node], from: usize, direction: Direction, wrap_around: bool) -> Option<usize> {
+    if let Some(next) = neighbour(nodes, from, direction) {
+        return Some(next);
+    }
+    if !wrap_around {
+        return None;
+    }
+
+    let mut furthest = from;
+    for _ in 0..nodes.len() {
+        match neighbour(nodes, furthest, direction.opposite()) {
+            Some(next) if next != from => furthest = next,
+            _ => break,
+        }
+    }
+    Some(furthest).filter(|furthest| *furthest != from)
+}
+
+/// Where we think the pointer is on the active node's screen.  Tracked from relative motion.
+#[derive(Debug, Default)]
+pub(crate) struct Pointer {
+    x: i64,
+    y: i64,
+}
+
+impl Pointer {
+    /// We have no idea where the pointer is.  The middle is a good guess.
+    pub(crate) fn center(&mut self, screen: Option<&Screen>) {
+        if let Some(screen) = screen {
+            self.x = screen.width as i64 / 2;
+            self.y = screen.height as i64 / 2;
+        }
+    }
+
+    /// Move the pointer.  If that takes it off the screen, returns which edge it went over.
+    pub(crate) fn move_by(&mut self, dx: i32, dy: i32, screen: &Screen) -> Option<Direction> {
+        self.x += dx as i64;
+        self.y += dy as i64;
+        if self.x < 0 {
+            Some(Direction::Left)
+        } else if self.x >= screen.width as i64 {
+            Some(Direction::Right)
+        } else if self.y < 0 {
+            Some(Direction::Up)
+        } else if self.y >= screen.height as i64 {
+            Some(Direction::Down)
+        } else {
+            None
+        }
+    }
+
+    /// Keep the pointer on the screen
+    pub(crate) fn clamp(&mut self, screen: &Screen) {
+        self.x = self.x.clamp(0, (screen.width as i64 - 1).max(0));
+        self.y = self.y.clamp(0, (screen.height as i64 - 1).max(0));
+    }
+
+    /// The pointer went over the edge of `from` in the given direction.  Put it on the opposite
+    /// edge of `to` at the same relative position along that edge.
+    pub(crate) fn enter(&mut self, direction: Direction, from: &Screen, to: &Screen) {
+        self.clamp(from);
+        let scale = |v: i64, from: u32, to: u32| if from == 0 { 0 } else { v * to as i64 / from as i64 };
+        match direction {
+            Direction::Left => {
+                self.x = to.width as i64 - 1;
+                self.y = scale(self.y, from.height, to.height);
+            }
+            Direction::Right => {
+                self.x = 0;
+                self.y = scale(self.y, from.height, to.height);
+            }
+            Direction::Up => {
+                self.x = scale(self.x, from.width, to.width);
+                self.y = to.height as i64 - 1;
+            }
+            Direction::Down => {
+                self.x = scale(self.x, from.width, to.width);
+                self.y = 0;
+            }
+        }
+        self.clamp(to);
+    }
+
+    pub(crate) fn position(&self) -> (i32, i32) {
+        (self.x as i32, self.y as i32)
+    }
+}
@@ -0,0 +1,1703 @@
+use std::collections::{BTreeMap, HashSet};
+use std::convert::TryFrom;
+use std::iter::FromIterator;
+use std::time::{Duration, Instant, SystemTime};
+
+use itertools::Itertools;
+use num_traits::cast::ToPrimitive;
+use prost_wkt_types::Timestamp;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::time::sleep;
+
+use rkvm2_config::{Config, NodeEntry, NotificationConfig, NotificationEvent};
+use rkvm2_proto::{ActiveNodeChangedEvent, Button, ClaimEvent, ClipboardEvent, ControlCommand, ControlRequest, Header, InputEvent, InputKind, InputTarget, Key, KeyEvent, Message, MirrorEvent, NodeInfo, NodeStatus, NotifyEvent, PingEvent, PointerPositionEvent, PongEvent, ResponseCode, ResponseHeader, RunCommandEvent, Screen, StatusEvent, WakeEvent};
+use rkvm2_proto::header::HeaderType;
+use rkvm2_proto::input_event::InputEventType;
+use rkvm2_proto::message::Payload;
+
+use crate::clock::Clock;
+use crate::desktop::{Clipboard, Notification, Notifier};
+use crate::layout::{Direction, Pointer};
+use crate::link::{ClockOffset, LinkStats};
+use crate::state::{SavedState, StateStore};
+
+pub mod clock;
+mod command;
+pub mod conn;
+pub mod desktop;
+mod layout;
+mod link;
+#[cfg(test)]
+mod sim;
+pub mod state;
+#[cfg(test)]
+mod tests;
+
+const PING_INTERVAL: Duration = Duration::from_secs(3);
+const NODE_TIMEOUT: Duration = Duration::from_secs(9);
+/// Pointer motion older than this is dropped rather than replayed late
+const STALE_INPUT: Duration = Duration::from_millis(250);
+/// Keys that might start a binding are let through if the rest of it doesn't follow this quickly
+const HOLD_TIMEOUT: Duration = Duration::from_millis(200);
+
+trait Action: Send {
+    /// False if there was nothing to do, so the keys that fired it are ordinary keys after all
+    fn act(&self, app: &App) -> bool;
+}
+
+/// Which node an [ActiveNodeChangeAction] switches to
+enum NodeTarget {
+    Next,
+    Commander,
+    Named(String),
+    Direction(Direction, bool),
+}
+
+struct ActiveNodeChangeAction {
+    target: NodeTarget,
+    input: InputTarget,
+}
+impl ActiveNodeChangeAction {
+    fn for_next_node() -> Self {
+        Self {
+            target: NodeTarget::Next,
+            input: InputTarget::All,
+        }
+    }
+    fn for_commander() -> Self {
+        Self {
+            target: NodeTarget::Commander,
+            input: InputTarget::All,
+        }
+    }
+    fn for_named_node(name: String, input: InputTarget) -> Self {
+        Self {
+            target: NodeTarget::Named(name),
+            input,
+        }
+    }
+    fn for_direction(direction: Direction, wrap_around: bool) -> Self {
+        Self {
+            target: NodeTarget::Direction(direction, wrap_around),
+            input: InputTarget::All,
+        }
+    }
+}
+impl Action for ActiveNodeChangeAction {
+    fn act(&self, app: &App) -> bool {
+        let next_node_index = match &self.target {
+            NodeTarget::Next => app.active_index().map_or(0, |index| (index + 1) % app.nodes.len()),
+            NodeTarget::Commander => match app.commander_index() {
+                Some(next_node_index) => next_node_index,
+                None => return false,
+            },
+            NodeTarget::Named(name) => match app.nodes.iter().position(|n| n.is_called(name)) {
+                Some(next_node_index) => next_node_index,
+                None => {
+                    log::warn!("No node called {}", name);
+                    return false;
+                }
+            },
+            NodeTarget::Direction(direction, wrap_around) => {
+                // nothing that way, so the keys are probably for the desktop, like switching workspaces
+                match app.active_index()
+                    .and_then(|index| layout::next_in_direction(&app.nodes, index, *direction, *wrap_around)) {
+                    Some(next_node_index) => next_node_index,
+                    None => return false,
+                }
+            }
+        };
+        match app.nodes.get(next_node_index) {
+            Some(next_node) => {
+                let id = next_node.id.clone();
+                app.send_to_loopback(Message {
+                    header: None,
+                    payload: Some(Payload::ActiveNodeChangedEvent(ActiveNodeChangedEvent {
+                        id,
+                        target: self.input as i32,
+                    })),
+                });
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Run a shell command on this node
+struct CommandAction {
+    command: String,
+}
+impl Action for CommandAction {
+    fn act(&self, _app: &App) -> bool {
+        command::run(&self.command);
+        true
+    }
+}
+
+/// Ask the active node to run one of its allowed commands
+struct RemoteCommandAction {
+    name: String,
+}
+impl Action for RemoteCommandAction {
+    fn act(&self, app: &App) -> bool {
+        app.send_to_loopback(Message {
+            header: None,
+            payload: Some(Payload::RunCommandEvent(RunCommandEvent {
+                name: self.name.clone(),
+            })),
+        });
+        true
+    }
+}
+
+/// Start typing into some nodes at once, or stop if we already are
+struct MirrorAction {
+    names: Vec<String>,
+}
+impl Action for MirrorAction {
+    fn act(&self, app: &App) -> bool {
+        let ids = if app.mirror.is_empty() {
+            self.names.iter()
+                .filter_map(|name| match app.nodes.iter().find(|n| n.is_called(name)) {
+                    Some(node) => Some(node.id.clone()),
+                    None => {
+                        log::warn!("No node called {}", name);
+                        None
+                    }
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+        app.send_to_loopback(Message {
+            header: None,
+            payload: Some(Payload::MirrorEvent(MirrorEvent { ids })),
+        });
+        true
+    }
+}
+
+/// How far pressing some keys gets a [KeyBinding]
+#[derive(Debug, PartialEq, Eq)]
+enum Step {
+    /// Not this binding
+    None,
+    /// Could be this binding if more keys are pressed
+    Partial,
+    /// Finished a step of this binding but there are more to go
+    Complete,
+    /// That's the binding
+    Fire,
+}
+
+struct KeyBinding {
+    /// Chords to press one after the other.  Usually just the one.
+    steps: Vec<HashSet<i32>>,
+    /// How long to wait for the next step
+    timeout: Duration,
+    action: Box<dyn Action>,
+}
+impl KeyBinding {
+    fn new(keys: Vec<Key>, action: Box<dyn Action>) -> Self {
+        Self::sequence(vec![keys.iter().map(|k| k.to_i32().unwrap()).collect()], Duration::ZERO, action)
+    }
+    /// A binding for key (and button) codes pressed one chord after the other
+    fn sequence(steps: Vec<Vec<i32>>, timeout: Duration, action: Box<dyn Action>) -> Self {
+        Self {
+            steps: steps.into_iter()
+                .map(HashSet::from_iter)
+                .collect(),
+            timeout,
+            action
+        }
+    }
+    /// True if this binding could still fire after the given steps, the last of which was
+    /// completed `elapsed` ago
+    fn alive(&self, sequence: &[HashSet<i32>], elapsed: Duration) -> bool {
+        self.steps.len() > sequence.len()
+            && self.steps.starts_with(sequence)
+            && (sequence.is_empty() || elapsed < self.timeout)
+    }
+    fn step(&self, sequence: &[HashSet<i32>], elapsed: Duration, keys: &HashSet<i32>) -> Step {
+        if !self.alive(sequence, elapsed) {
+            return Step::None;
+        }
+        let next = &self.steps[sequence.len()];
+        if next == keys {
+            if sequence.len() + 1 == self.steps.len() { Step::Fire } else { Step::Complete }
+        } else if next.is_superset(keys) {
+            Step::Partial
+        } else {
+            Step::None
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Node {
+    /// True if this node can be the commander
+    commander: bool,
+    /// Of the commanders, the reachable one with the latest claim, then the highest priority, is in charge
+    priority: u32,
+    /// Latest claim to be the commander.  See [App::claim_command].
+    claim: u64,
+    local: bool,
+    id: String,
+    name: String,
+    /// What the user called this node in the config, if anything
+    alias: String,
+    last_heard_from: Instant,
+    link: LinkStats,
+    clock: ClockOffset,
+    screen: Option<Screen>,
+    /// What the node told us about itself
+    info: NodeInfo,
+}
+impl Node {
+    fn expired(&self, now: Instant) -> bool {
+        !self.local
+            && now.duration_since(self.last_heard_from) > NODE_TIMEOUT
+    }
+
+    /// True if this node goes by the given id, name or alias
+    fn is_called(&self, name: &str) -> bool {
+        self.id == name || self.name == name || (!self.alias.is_empty() && self.alias == name)
+    }
+
+    /// True if the node's input writer can write this kind of input.  Nodes that don't say get the benefit of the doubt.
+    fn can_write(&self, kind: InputKind) -> bool {
+        !self.info.input_kinds_announced || self.info.input_kinds.contains(&(kind as i32))
+    }
+
+    /// The name to show the user
+    fn display_name(&self) -> &str {
+        if self.alias.is_empty() {
+            &self.name
+        } else {
+            &self.alias
+        }
+    }
+}
+
+/// The outside world, as far as the [App] is concerned
+pub struct Platform {
+    pub clock: Box<dyn Clock>,
+    pub clipboard: Box<dyn Clipboard>,
+    pub notifier: Box<dyn Notifier>,
+    pub state: Box<dyn StateStore>,
+    /// What this node is and what it can do, for the other nodes.  The uptime is filled in as we go.
+    pub info: NodeInfo,
+}
+
+/// Tracks the nodes and routes input between them
+pub struct App {
+    group: String,
+    /// Known nodes, kept in [App::arrange_nodes] order
+    nodes: Vec<Node>,
+    node_order: Vec<NodeEntry>,
+    keys: HashSet<i32>,
+    /// Mouse buttons held down.  Kept apart from the keys so clicking doesn't get in the way of bindings.
+    buttons: HashSet<i32>,
+    /// Mouse buttons used in a binding
+    binding_buttons: HashSet<i32>,
+    /// Key events that might be part of a binding, not sent on yet
+    held_keys: Vec<KeyEvent>,
+    /// When the first of the held keys was held back
+    held_time: Instant,
+    /// Steps of a binding sequence completed so far
+    sequence: Vec<HashSet<i32>>,
+    /// When the last step of the sequence was completed
+    sequence_time: Instant,
+    /// Keys of a binding that fired.  Their releases are dropped.
+    consumed_keys: HashSet<i32>,
+    /// The id of the node receiving input, if we know it
+    active_node: Option<String>,
+    /// The id of the node receiving pointer motion and clicks if it isn't the active node
+    pointer_node: Option<String>,
+    /// The id of the node receiving scrolling if it isn't the active node
+    scroll_node: Option<String>,
+    /// The ids of the nodes getting the keyboard as well as the active node
+    mirror: Vec<String>,
+    /// The node that was active before a restart.  We switch back to it when it pings.
+    restore_node: Option<String>,
+    /// What we last saved
+    saved_state: SavedState,
+    /// When our own keyboard or mouse was last used, or the active node last changed
+    last_input: Instant,
+    /// When we started, for the uptime
+    started: Instant,
+    pointer: Pointer,
+    key_bindings: Vec<KeyBinding>,
+    /// The id of the node in charge, as last announced
+    commander_id: Option<String>,
+    /// True if using our own keyboard or mouse makes us the commander
+    dynamic_commander: bool,
+    /// Commands the commander may ask us to run, by name
+    commands: BTreeMap<String, String>,
+    notifications: NotificationConfig,
+    input_sender: UnboundedSender<Message>,
+    net_sender: UnboundedSender<Message>,
+    message_sender: UnboundedSender<Message>,
+    control_sender: UnboundedSender<Message>,
+    /// Ids of the control requests watching the status
+    watchers: Vec<String>,
+    /// The status the watchers were last told about, without the numbers that change with every ping
+    watched: Option<StatusEvent>,
+    platform: Platform,
+    ping_sequence: u64,
+}
+
+impl App {
+    /// Input goes out the input sender, other nodes are reached through the net sender,
+    /// messages for the app itself go to the message sender and responses to control requests
+    /// go to the control sender.  Feed everything that comes back to [App::handle_message].
+    pub fn new(
+        id: String,
+        config: Config,
+        mut platform: Platform,
+        input_sender: UnboundedSender<Message>,
+        net_sender: UnboundedSender<Message>,
+        message_sender: UnboundedSender<Message>,
+        control_sender: UnboundedSender<Message>,
+    ) -> Self {
+        let my_node = Node {
+            commander: config.commander,
+            priority: config.commander_priority,
+            claim: 0,
+            local: true,
+            id,
+            name: config.name.clone(),
+            alias: String::new(),
+            last_heard_from: platform.clock.now(),
+            link: LinkStats::default(),
+            clock: ClockOffset::default(),
+            screen: Some(Screen {
+                width: config.screen_width,
+                height: config.screen_height,
+                left: config.left.clone(),
+                right: config.right.clone(),
+                above: config.above.clone(),
+                below: config.below.clone(),
+            }),
+            info: platform.info.clone(),
+        };
+        let mut pointer = Pointer::default();
+        pointer.center(my_node.screen.as_ref());
+
+        let mut key_bindings = vec![
+            KeyBinding::new(config.switch_keys, Box::new(ActiveNodeChangeAction::for_next_node())),
+            KeyBinding::new(config.commander_keys, Box::new(ActiveNodeChangeAction::for_commander())),
+            KeyBinding::new(config.left_keys, Box::new(ActiveNodeChangeAction::for_direction(Direction::Left, config.wrap_around))),
+            KeyBinding::new(config.right_keys, Box::new(ActiveNodeChangeAction::for_direction(Direction::Right, config.wrap_around))),
+            KeyBinding::new(config.up_keys, Box::new(ActiveNodeChangeAction::for_direction(Direction::Up, config.wrap_around))),
+            KeyBinding::new(config.down_keys, Box::new(ActiveNodeChangeAction::for_direction(Direction::Down, config.wrap_around))),
+        ];
+        for (keys, binding) in &config.bindings {
+            let steps = match rkvm2_config::parse_key_sequence(keys) {
+                Ok(steps) => steps,
+                Err(e) => {
+                    log::warn!("Ignoring binding: {}", e);
+                    continue;
+                }
+            };
+            // the same chord over and over is a double tap, which needs to be quick
+            let timeout = if steps.iter().all_equal() {
+                Duration::from_millis(config.double_tap_timeout)
+            } else {
+                Duration::from_millis(config.sequence_timeout)
+            };
+            for (name, input) in [
+                (&binding.switch_to, InputTarget::All),
+                (&binding.switch_keyboard_to, InputTarget::Keyboard),
+                (&binding.switch_pointer_to, InputTarget::Pointer),
+                (&binding.switch_scroll_to, InputTarget::Scroll),
+            ] {
+                if let Some(name) = name {
+                    key_bindings.push(KeyBinding::sequence(steps.clone(), timeout, Box::new(ActiveNodeChangeAction::for_named_node(name.clone(), input))));
+                }
+            }
+            if let Some(command) = &binding.run {
+                key_bindings.push(KeyBinding::sequence(steps.clone(), timeout, Box::new(CommandAction { command: command.clone() })));
+            }
+            if let Some(name) = &binding.run_on_active {
+                key_bindings.push(KeyBinding::sequence(steps.clone(), timeout, Box::new(RemoteCommandAction { name: name.clone() })));
+            }
+            if !binding.mirror.is_empty() {
+                key_bindings.push(KeyBinding::sequence(steps.clone(), timeout, Box::new(MirrorAction { names: binding.mirror.clone() })));
+            }
+        }
+
+        let binding_buttons = key_bindings.iter()
+            .flat_map(|b| b.steps.iter().flatten())
+            .filter(|code| is_button(**code))
+            .cloned()
+            .collect();
+        let active_node = if config.commander { Some(my_node.id.clone()) } else { None };
+        let saved_state = platform.state.load().unwrap_or_default();
+        let restore_node = saved_state.active_node.clone().filter(|id| id != &my_node.id);
+        if let Some(id) = &restore_node {
+            log::info!("Switching back to {} when it pings", id);
+        }
+        let mut app = Self {
+            group: config.group.clone(),
+            nodes: vec![my_node],
+            node_order: config.nodes.clone(),
+            keys: Default::default(),
+            buttons: HashSet::new(),
+            binding_buttons,
+            held_keys: Vec::new(),
+            held_time: platform.clock.now(),
+            sequence: Vec::new(),
+            sequence_time: platform.clock.now(),
+            consumed_keys: HashSet::new(),
+            active_node,
+            pointer_node: None,
+            scroll_node: None,
+            mirror: Vec::new(),
+            restore_node,
+            saved_state,
+            last_input: platform.clock.now(),
+            started: platform.clock.now(),
+            pointer,
+            key_bindings,
+            commander_id: None,
+            dynamic_commander: config.dynamic_commander,
+            commands: config.commands.clone(),
+            notifications: config.notifications.clone(),
+            input_sender,
+            net_sender,
+            message_sender,
+            control_sender,
+            watchers: Vec::new(),
+            watched: None,
+            platform,
+            ping_sequence: 0,
+        };
+        app.arrange_nodes();
+        app.commander_id = app.commander().map(|n| n.id.clone());
+        app
+    }
+
+    /// Apply the configured aliases and put the nodes in order: the configured ones first, then
+    /// the rest by name.  Indexes into the nodes only make sense until the next call.
+    fn arrange_nodes(&mut self) {
+        let order = &self.node_order;
+        for node in self.nodes.iter_mut() {
+            node.alias = order.iter()
+                .find(|entry| node.id == entry.name || node.name == entry.name)
+                .map(|entry| entry.alias.clone())
+                .unwrap_or_default();
+        }
+        self.nodes.sort_by_cached_key(|node| (
+            order.iter().position(|entry| node.is_called(&entry.name)).unwrap_or(usize::MAX),
+            node.name.clone(),
+            node.id.clone(),
+        ));
+    }
+
+    fn my_node(&self) -> &Node {
+        self.nodes.iter().find(|n| n.local).unwrap()
+    }
+
+    /// The node in charge of routing input: the reachable commander with the latest claim, then
+    /// the highest priority.  Ties go to the highest id so every node picks the same one.
+    fn commander_index(&self) -> Option<usize> {
+        self.nodes.iter()
+            .enumerate()
+            .filter(|(_, n)| n.commander)
+            .max_by(|(_, a), (_, b)| (a.claim, a.priority, &a.id).cmp(&(b.claim, b.priority, &b.id)))
+            .map(|(index, _)| index)
+    }
+
+    /// Our own keyboard or mouse was used so take over as the commander.  Claims count up so the
+    /// latest one wins everywhere, whatever order they arrive in.
+    fn claim_command(&mut self) {
+        let claim = self.nodes.iter().map(|n| n.claim).max().unwrap_or(0) + 1;
+        let my_node = self.nodes.iter_mut().find(|n| n.local).unwrap();
+        my_node.commander = true;
+        my_node.claim = claim;
+        self.elect();
+        self.send_to_net(Message {
+            header: None,
+            payload: Some(Payload::ClaimEvent(ClaimEvent {
+                claim,
+            })),
+        }, "");
+
+        if self.active().is_none() {
+            let id = self.my_node().id.clone();
+            self.handle_active_node_changed(false, &ActiveNodeChangedEvent { id, target: InputTarget::All as i32 });
+        }
+    }
+
+    fn commander(&self) -> Option<&Node> {
+        self.commander_index().map(|index| &self.nodes[index])
+    }
+
+    /// True if this node is in charge
+    fn is_commander(&self) -> bool {
+        self.commander().is_some_and(|n| n.local)
+    }
+
+    /// See if the commander changed since nodes came and went
+    fn elect(&mut self) {
+        let commander = self.commander().map(|n| (n.id.clone(), n.display_name().to_string(), n.local));
+        let commander_id = commander.as_ref().map(|(id, _, _)| id.clone());
+        if commander_id == self.commander_id {
+            return;
+        }
+        let previous = std::mem::replace(&mut self.commander_id, commander_id);
+        match commander {
+            Some((_, name, true)) => {
+                log::info!("Taking command");
+                self.notify(NotificationEvent::Commander, &name, "I have the command");
+            }
+            Some((id, name, false)) => {
+                log::info!("{} ({}) has the command", name, id);
+                // finding the commander for the first time isn't news
+                if previous.is_some() {
+                    self.notify(NotificationEvent::Commander, &name, format!("{} has the command", name).as_str());
+                }
+            }
+            None => {
+                log::info!("No commander");
+            }
+        }
+    }
+
+    fn active_index(&self) -> Option<usize> {
+        let active_node = self.active_node.as_ref()?;
+        self.nodes.iter().position(|n| &n.id == active_node)
+    }
+
+    fn active(&self) -> Option<&Node> {
+        self.active_index().map(|index| &self.nodes[index])
+    }
+
+    /// The id of the node receiving the given input
+    fn target_id(&self, input: InputTarget) -> Option<&String> {
+        match input {
+            InputTarget::All | InputTarget::Keyboard => self.active_node.as_ref(),
+            InputTarget::Pointer => self.pointer_node.as_ref().or(self.active_node.as_ref()),
+            InputTarget::Scroll => self.scroll_node.as_ref().or(self.active_node.as_ref()),
+        }
+    }
+
+    fn target_index(&self, input: InputTarget) -> Option<usize> {
+        let id = self.target_id(input)?;
+        self.nodes.iter().position(|n| &n.id == id)
+    }
+
+    /// Ping the other nodes every few seconds, which also expires the ones we stop hearing from
+    pub fn start_pinging(&self) {
+        let ping_sender = self.message_sender.clone();
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = ping_sender.send(Message {
+                    header: None,
+                    payload: Some(Payload::PingEvent(PingEvent::default())),
+                }) {
+                    log::warn!("Failed to send ping {}", e);
+                }
+                sleep(PING_INTERVAL).await;
+            }
+        });
+    }
+
+    fn send_to_input(&self, message: Message) {
+        if let Err(e) = self.input_sender.send(message) {
+            log::warn!("Failed to send message {}", e);
+        }
+    }
+
+    fn send_to_net(&self, mut message: Message, to_id: &str) {
+        let my_node = self.my_node();
+        let header = message.header.get_or_insert(Header::default());
+        header.from_id = my_node.id.clone();
+        header.to_id = to_id.to_string();
+        header.group = self.group.clone();
+        if let Err(e) = self.net_sender.send(message) {
+            log::warn!("Failed to send message {}", e);
+        }
+    }
+
+    fn send_to_loopback(&self, message: Message) {
+        if let Err(e) = self.message_sender.send(message) {
+            log::warn!("Failed to send message {}", e);
+        }
+    }
+
+    pub fn handle_message(&mut self, message: Message) {
+        let mut origin = String::new();
+        let mut from_net = false;
+
+        if let Some(header) = &message.header {
+            let my_node = self.my_node();
+            if header.from_id == my_node.id {
+                // external messages that are from me
+                return;
+            } else if !header.to_id.is_empty() && header.to_id != my_node.id {
+                // external messages that aren't for me
+                return;
+            } else if !header.from_id.is_empty() && header.group != self.group {
+                // external messages from another setup sharing the network
+                log::trace!("Ignoring message from {} in group {}", header.from_id, header.group);
+                return;
+            } else if !header.from_id.is_empty() {
+                // external messages that aren't for me
+                origin = header.from_id.clone();
+            }
+            from_net = !origin.is_empty();
+        }
+        log::trace!("{:?} {:?}", message, self.elapsed_time(&message, &origin));
+
+        // the status doesn't change while typing, so don't check on every key
+        let watch = !matches!(message.payload, Some(Payload::InputEvent(_) | Payload::WakeEvent(_)));
+        if let Some(payload) = &message.payload {
+            match payload {
+                Payload::PingEvent(ping) => {
+                    self.handle_ping(from_net, origin, ping);
+                }
+                Payload::InputEvent(_) => {
+                    self.handle_input(from_net, origin, message);
+                }
+                Payload::ActiveNodeChangedEvent(active_node_changed) => {
+                    self.handle_active_node_changed(from_net, active_node_changed)
+                }
+                Payload::ClipboardEvent(clipboard) => {
+                    self.handle_clipboard(clipboard);
+                }
+                Payload::PongEvent(pong) => {
+                    self.handle_pong(origin, pong);
+                }
+                Payload::ClaimEvent(claim) => {
+                    self.handle_claim(origin, claim);
+                }
+                Payload::RunCommandEvent(run_command) => {
+                    self.handle_run_command(from_net, origin, run_command);
+                }
+                Payload::MirrorEvent(mirror) => {
+                    self.handle_mirror(from_net, mirror);
+                }
+                Payload::NotifyEvent(notify) => {
+                    // anything local was captured from the desktop, so it's already on screen here
+                    self.handle_notify(from_net, origin, notify, true);
+                }
+                Payload::ControlRequest(request) => {
+                    if !from_net {
+                        self.handle_control(message.header.as_ref(), request);
+                    }
+                }
+                Payload::WakeEvent(_) => {
+                    if !from_net {
+                        self.handle_wake();
+                    }
+                }
+                _ => {
+                    if !from_net {
+                        self.send_to_net(message, "");
+                        return;
+                    }
+                }
+            }
+            if watch {
+                self.update_watchers();
+            }
+        }
+    }
+
+    fn handle_active_node_changed(&mut self, from_net: bool, active_node_changed: &ActiveNodeChangedEvent) {
+        let index = match self.nodes.iter().position(|n| n.id == active_node_changed.id) {
+            Some(index) => index,
+            None => {
+                log::debug!("New active node {} not found", active_node_changed.id);
+                return;
+            }
+        };
+        let node = &self.nodes[index];
+        let input = active_node_changed.target();
+        let id = Some(node.id.clone());
+        let keyboard = self.target_id(InputTarget::Keyboard).cloned();
+        let pointer = self.target_id(InputTarget::Pointer).cloned();
+        let scroll = self.target_id(InputTarget::Scroll).cloned();
+        let (new_keyboard, new_pointer, new_scroll) = match input {
+            InputTarget::All => (id.clone(), id.clone(), id.clone()),
+            InputTarget::Keyboard => (id.clone(), pointer.clone(), scroll.clone()),
+            InputTarget::Pointer => (keyboard.clone(), id.clone(), scroll.clone()),
+            InputTarget::Scroll => (keyboard.clone(), pointer.clone(), id.clone()),
+        };
+        if (&new_keyboard, &new_pointer, &new_scroll) == (&keyboard, &pointer, &scroll) {
+            return;
+        }
+
+        // held back keys belong to the node that had the keyboard when they were pressed
+        for key_event in self.release_held_keys() {
+            self.route_input(key_event_message(key_event));
+        }
+        let node = &self.nodes[index];
+
+        let my_id = Some(self.my_node().id.clone());
+        // my node has the keyboard
+        if keyboard == my_id && new_keyboard != keyboard {
+            // if I'm about to be switched, send my clip contents
+            let text = self.platform.clipboard.get_text();
+            self.share_clipboard(text);
+
+            // release any keybinding keys
+            for key in &self.keys {
+                self.send_to_input(key_event_message(KeyEvent { key: *key, down: false }));
+            }
+            self.keys.clear();
+        }
+        // my node has the pointer
+        if pointer == my_id && new_pointer != pointer {
+            for button in &self.buttons {
+                self.send_to_input(key_event_message(KeyEvent { key: *button, down: false }));
+            }
+            self.buttons.clear();
+        }
+
+        // switch the active node
+        let follows_keyboard = |target: Option<String>| target.filter(|t| Some(t) != new_keyboard.as_ref());
+        self.pointer_node = follows_keyboard(new_pointer.clone());
+        self.scroll_node = follows_keyboard(new_scroll);
+        self.active_node = new_keyboard;
+        self.last_input = self.platform.clock.now();
+        // we've moved on
+        self.restore_node = None;
+        if new_pointer != pointer {
+            self.pointer.center(node.screen.as_ref());
+        }
+        log::debug!("Switched {:?} to {:?}", input, node);
+
+        let active_node_id = node.id.clone();
+        let active_node_name = node.display_name().to_string();
+        let what = match input {
+            InputTarget::All => "the conn",
+            InputTarget::Keyboard => "the keyboard",
+            InputTarget::Pointer => "the pointer",
+            InputTarget::Scroll => "the scroll wheel",
+        };
+        let message = if node.local {
+            format!("I have {}", what)
+        } else if node.link.is_slow() {
+            log::warn!("Switched to {} over a slow link ({})", active_node_name, node.link);
+            format!("{} has {} (slow link: {})", active_node_name, what, node.link)
+        } else {
+            format!("{} has {}", active_node_name, what)
+        };
+        self.notify(NotificationEvent::Switch, &active_node_name, &message);
+
+        if !from_net {
+            self.send_to_net(Message {
+                header: None,
+                payload: Some(Payload::ActiveNodeChangedEvent(ActiveNodeChangedEvent {
+                    id: active_node_id,
+                    target: input as i32,
+                })),
+            }, "");
+        }
+        self.save_state();
+        self.update_watchers();
+    }
+
+    /// Send my clip contents to the other nodes
+    fn share_clipboard(&self, text: Result<String, String>) -> bool {
+        match text {
+            Ok(text) => {
+                log::debug!("Send clip text\n{}", text);
+                self.send_to_net(
+                    Message {
+                        header: None,
+                        payload: Some(Payload::ClipboardEvent(ClipboardEvent {
+                            data: text.into_bytes(),
+                            mime_type: "".to_string(),
+                        })),
+                    },
+                    "",
+                );
+                true
+            }
+            Err(e) => {
+                log::warn!("Failed to get clipboard text {}", e);
+                false
+            }
+        }
+    }
+
+    /// Save the active node if it changed.  Still waiting for the node we had before, so keep that.
+    fn save_state(&mut self) {
+        let state = SavedState {
+            active_node: self.restore_node.clone().or_else(|| self.active_node.clone()),
+        };
+        if state != self.saved_state {
+            self.platform.state.save(&state);
+            self.saved_state = state;
+        }
+    }
+
+    fn handle_mirror(&mut self, from_net: bool, mirror: &MirrorEvent) {
+        if self.mirror == mirror.ids {
+            return;
+        }
+
+        // release any keys the mirror left pressed here
+        let my_id = self.my_node().id.clone();
+        if self.mirror.contains(&my_id) && !mirror.ids.contains(&my_id) && self.active_node.as_ref() != Some(&my_id) {
+            for key in &self.keys {
+                self.send_to_input(key_event_message(KeyEvent { key: *key, down: false }));
+            }
+            self.keys.clear();
+        }
+
+        self.mirror = mirror.ids.clone();
+        if self.mirror.is_empty() {
+            log::info!("Stopped mirroring");
+            self.notify(NotificationEvent::Mirror, "", "Stopped mirroring");
+        } else {
+            let names = self.mirror.iter()
+                .filter_map(|id| self.nodes.iter().find(|n| &n.id == id))
+                .map(|n| n.display_name())
+                .join(", ");
+            log::info!("Mirroring to {}", names);
+            self.notify(NotificationEvent::Mirror, &names, format!("Mirroring to {}", names).as_str());
+        }
+
+        if !from_net {
+            self.send_to_net(Message {
+                header: None,
+                payload: Some(Payload::MirrorEvent(mirror.clone())),
+            }, "");
+        }
+    }
+
+    fn handle_input(&mut self, from_net: bool, origin: String, message: Message) {
+        // late pointer motion just makes the pointer jump around.  Keys are never dropped
+        // or they could get stuck.
+        if from_net {
+            if let Some(Payload::InputEvent(InputEvent {
+                input_event_type: Some(InputEventType::X(_) | InputEventType::Y(_) | InputEventType::Wheel(_))
+            })) = &message.payload {
+                if let Some(elapsed) = self.elapsed_time(&message, &origin) {
+                    if elapsed > STALE_INPUT {
+                        log::debug!("Dropping stale input from {} ({:?} old)", origin, elapsed);
+                        return;
+                    }
+                }
+            }
+        }
+
+        // track the keys.  Any keys remaining after a switch should be released
+        let key_event = match &message {
+            Message { header: _, payload: Some(Payload::InputEvent(InputEvent { input_event_type: Some(InputEventType::Key(key_event)) })) } => {
+                let keys = if is_button(key_event.key) { &mut self.buttons } else { &mut self.keys };
+                match key_event.down {
+                    true => keys.insert(key_event.key),
+                    false => keys.remove(&key_event.key),
+                };
+                Some(key_event.clone())
+            }
+            _ => None,
+        };
+
+        if !from_net {
+            self.last_input = self.platform.clock.now();
+        }
+
+        if !from_net && self.dynamic_commander && !self.is_commander() {
+            self.claim_command();
+        }
+
+        if !from_net && !self.is_commander() {
+            // a backup commander's own keyboard and mouse, while another commander is in charge
+            self.send_to_input(message);
+            return;
+        }
+        if from_net || !self.is_commander() {
+            self.route_input(message);
+            return;
+        }
+
+        if let Some(key_event) = key_event {
+            for key_event in self.filter_key(key_event) {
+                self.route_input(key_event_message(key_event));
+            }
+            return;
+        }
+
+        // follow the pointer so we can switch when it goes over the edge of the screen
+        let motion = match &message.payload {
+            Some(Payload::InputEvent(InputEvent { input_event_type: Some(InputEventType::X(m)) })) => Some((m.delta, 0)),
+            Some(Payload::InputEvent(InputEvent { input_event_type: Some(InputEventType::Y(m)) })) => Some((0, m.delta)),
+            _ => None,
+        };
+        if let Some((dx, dy)) = motion {
+            if self.move_pointer(dx, dy) {
+                return;
+            }
+        } else {
+            // scrolls with held back keys are ctrl+scroll and friends, not a binding
+            for key_event in self.release_held_keys() {
+                self.route_input(key_event_message(key_event));
+            }
+        }
+
+        self.route_input(message);
+    }
+
+    /// Decide what to do with a key from our own keyboard.  Presses that could be the start of a
+    /// binding are held back until we know, and the keys of a binding that fired are dropped.
+    /// Returns the key events to send on, in order.
+    fn filter_key(&mut self, key_event: KeyEvent) -> Vec<KeyEvent> {
+        if is_button(key_event.key) && !self.binding_buttons.contains(&key_event.key) {
+            // clicks with held back keys are ctrl+click and friends, not a binding
+            let mut key_events = self.release_held_keys();
+            key_events.push(key_event);
+            return key_events;
+        }
+
+        let elapsed = self.platform.clock.now().duration_since(self.sequence_time);
+        if !self.sequence.is_empty() && !self.key_bindings.iter().any(|b| b.alive(&self.sequence, elapsed)) {
+            // too slow or went off script.  Start over with this key.
+            let mut key_events = self.release_held_keys();
+            key_events.extend(self.filter_key(key_event));
+            return key_events;
+        }
+
+        if key_event.down {
+            // the keys could carry on the sequence so far or start another binding
+            let continued = self.binding_steps(&self.sequence, elapsed);
+            let restarted = self.binding_steps(&[], elapsed);
+            for steps in [&continued, &restarted] {
+                if steps.contains(&Step::Fire) {
+                    let mut acted = false;
+                    for (key_binding, step) in self.key_bindings.iter().zip(steps) {
+                        if *step == Step::Fire {
+                            acted |= key_binding.action.act(self);
+                        }
+                    }
+                    if !acted {
+                        let mut key_events = self.release_held_keys();
+                        key_events.push(key_event);
+                        return key_events;
+                    }
+                    // keys already let through need their releases to follow them
+                    let held = self.held_keys.iter().filter(|k| k.down).map(|k| k.key);
+                    self.consumed_keys.extend(held.chain([key_event.key]));
+                    self.held_keys.clear();
+                    self.sequence.clear();
+                    return vec![];
+                }
+            }
+            if continued.contains(&Step::Complete) {
+                self.sequence.push(self.binding_keys());
+            } else if restarted.contains(&Step::Complete) {
+                self.sequence = vec![self.binding_keys()];
+            }
+            if continued.contains(&Step::Complete) || restarted.contains(&Step::Complete) {
+                self.sequence_time = self.platform.clock.now();
+                // let the keys through when the bindings that could carry on run out of time
+                let timeouts: HashSet<Duration> = self.key_bindings.iter()
+                    .filter(|b| b.alive(&self.sequence, Duration::ZERO))
+                    .map(|b| b.timeout)
+                    .collect();
+                for timeout in timeouts {
+                    self.wake_after(timeout);
+                }
+                self.hold_key(key_event);
+                return vec![];
+            }
+            if continued.contains(&Step::Partial) || restarted.contains(&Step::Partial) {
+                self.hold_key(key_event);
+                return vec![];
+            }
+        } else {
+            if self.consumed_keys.remove(&key_event.key) {
+                return vec![];
+            }
+            if !self.sequence.is_empty() {
+                // part of the sequence so far
+                self.hold_key(key_event);
+                return vec![];
+            }
+            if !self.held_keys.iter().any(|k| k.key == key_event.key) {
+                return vec![key_event];
+            }
+        }
+        // not a binding after all
+        let mut key_events = self.release_held_keys();
+        key_events.push(key_event);
+        key_events
+    }
+
+    fn binding_steps(&self, sequence: &[HashSet<i32>], elapsed: Duration) -> Vec<Step> {
+        let keys = self.binding_keys();
+        self.key_bindings.iter()
+            .map(|b| b.step(sequence, elapsed, &keys))
+            .collect()
+    }
+
+    /// The keys and buttons held down that bindings care about
+    fn binding_keys(&self) -> HashSet<i32> {
+        self.keys.iter()
+            .chain(self.buttons.intersection(&self.binding_buttons))
+            .cloned()
+            .collect()
+    }
+
+    /// Hold back a key that might be part of a binding, for a while
+    fn hold_key(&mut self, key_event: KeyEvent) {
+        if self.held_keys.is_empty() {
+            self.held_time = self.platform.clock.now();
+            self.wake_after(HOLD_TIMEOUT);
+        }
+        self.held_keys.push(key_event);
+    }
+
+    /// Let held back keys through once they've waited too long for the rest of a binding
+    fn handle_wake(&mut self) {
+        if self.held_keys.is_empty() {
+            return;
+        }
+        let now = self.platform.clock.now();
+        let overdue = if self.sequence.is_empty() {
+            now.duration_since(self.held_time) >= HOLD_TIMEOUT
+        } else {
+            let elapsed = now.duration_since(self.sequence_time);
+            !self.key_bindings.iter().any(|b| b.alive(&self.sequence, elapsed))
+        };
+        if overdue {
+            for key_event in self.release_held_keys() {
+                self.route_input(key_event_message(key_event));
+            }
+        }
+    }
+
+    /// Get a [WakeEvent] after the delay
+    fn wake_after(&self, delay: Duration) {
+        self.platform.clock.send_after(delay, self.message_sender.clone(), Message {
+            header: None,
+            payload: Some(Payload::WakeEvent(WakeEvent {})),
+        });
+    }
+
+    /// Give up on a binding in progress.  Returns the key events held back for it.
+    fn release_held_keys(&mut self) -> Vec<KeyEvent> {
+        self.sequence.clear();
+        std::mem::take(&mut self.held_keys)
+    }
+
+    /// Send input on to wherever it should go
+    fn route_input(&self, message: Message) {
+        let input = match &message.payload {
+            Some(Payload::InputEvent(InputEvent { input_event_type: Some(input_event_type) })) => match input_event_type {
+                InputEventType::Key(key_event) if is_button(key_event.key) => InputTarget::Pointer,
+                InputEventType::Key(_) => InputTarget::Keyboard,
+                InputEventType::Wheel(_) => InputTarget::Scroll,
+                _ => InputTarget::Pointer,
+            },
+            _ => InputTarget::Keyboard,
+        };
+
+        // the keyboard goes to the mirror as well
+        if input == InputTarget::Keyboard && !self.mirror.is_empty() {
+            let keyboard = self.target_id(input);
+            for node in self.nodes.iter().filter(|n| keyboard == Some(&n.id) || self.mirror.contains(&n.id)) {
+                if node.local {
+                    self.send_to_input(message.clone());
+                } else if self.is_commander() {
+                    self.send_to_net(message.clone(), node.id.as_str());
+                }
+            }
+            return;
+        }
+
+        if let Some(active_node) = self.target_index(input).map(|index| &self.nodes[index]) {
+            if active_node.local {
+                self.send_to_input(message);
+                return;
+            }
+
+            if self.is_commander() {
+                self.send_to_net(message, active_node.id.as_str())
+            }
+        } else {
+            // we couldn't find the active node.  Could have expired and we haven't switched
+            // back to the commander yet.
+            self.send_to_input(message);
+        }
+    }
+
+    /// Move the virtual pointer.  If it goes over the edge of the pointer node's screen, switch to
+    /// the node on the other side and put the pointer in the matching spot there.  If the pointer
+    /// has gone its own way, only the pointer switches.
+    /// Returns true if we switched.
+    fn move_pointer(&mut self, dx: i32, dy: i32) -> bool {
+        let active_index = match self.target_index(InputTarget::Pointer) {
+            Some(active_index) => active_index,
+            None => return false,
+        };
+        let screen = match self.nodes[active_index].screen.clone() {
+            Some(screen) => screen,
+            None => return false,
+        };
+        if let Some(direction) = self.pointer.move_by(dx, dy, &screen) {
+            if let Some(next) = layout::neighbour(&self.nodes, active_index, direction) {
+                if let Some(next_screen) = self.nodes[next].screen.clone() {
+                    let next_id = self.nodes[next].id.clone();
+                    log::debug!("Pointer went {:?} to {}", direction, self.nodes[next].name);
+                    let mut pointer = std::mem::take(&mut self.pointer);
+                    pointer.enter(direction, &screen, &next_screen);
+                    let input = if self.pointer_node.is_some() { InputTarget::Pointer } else { InputTarget::All };
+                    self.handle_active_node_changed(false, &ActiveNodeChangedEvent {
+                        id: next_id.clone(),
+                        target: input as i32,
+                    });
+                    self.pointer = pointer;
+
+                    if !self.nodes[next].can_write(InputKind::AbsolutePointer) {
+                        // it'll have to carry on from wherever its pointer is
+                        log::debug!("{} can't place the pointer", self.nodes[next].name);
+                        return true;
+                    }
+                    let (x, y) = self.pointer.position();
+                    let position = Message {
+                        header: None,
+                        payload: Some(Payload::InputEvent(InputEvent {
+                            input_event_type: Some(InputEventType::Position(PointerPositionEvent { x, y })),
+                        })),
+                    };
+                    if self.nodes[next].local {
+                        self.send_to_input(position);
+                    } else {
+                        self.send_to_net(position, next_id.as_str());
+                    }
+                    return true;
+                }
+            }
+            self.pointer.clamp(&screen);
+        }
+        false
+    }
+
+    fn handle_ping(&mut self, from_net: bool, origin: String, ping: &PingEvent) {
+        if from_net {
+            // echo the ping back so the sender can measure the round trip and our clock offset
+            let receive_time = self.platform.clock.system_time();
+            self.send_to_net(Message {
+                header: None,
+                payload: Some(Payload::PongEvent(PongEvent {
+                    sequence: ping.sequence,
+                    ping_time: ping.time.clone(),
+                    receive_time: Some(receive_time.into()),
+                    time: Some(self.platform.clock.system_time().into()),
+                })),
+            }, origin.as_str());
+
+            let now = self.platform.clock.now();
+            let joined = !self.nodes.iter().any(|n| n.id == origin);
+            if let Some(node) =
+                self.nodes.iter_mut().find(|n| n.id == origin)
+            {
+                node.last_heard_from = now;
+                node.commander = ping.commander;
+                node.priority = ping.priority;
+                node.claim = node.claim.max(ping.claim);
+                node.screen = ping.screen.clone();
+                node.info = ping.info.clone().unwrap_or_default();
+                if !ping.name.is_empty() {
+                    node.name = ping.name.clone();
+                }
+            } else {
+                let info = ping.info.clone().unwrap_or_default();
+                log::info!("Adding {} ({}) running rkvm2 {} on {}", ping.name, origin, info.version, info.os);
+                let name = if ping.name.is_empty() { origin.clone() } else { ping.name.clone() };
+                self.nodes.push(Node {
+                    commander: ping.commander,
+                    priority: ping.priority,
+                    claim: ping.claim,
+                    local: false,
+                    id: origin.clone(),
+                    name,
+                    alias: String::new(),
+                    last_heard_from: now,
+                    link: LinkStats::default(),
+                    clock: ClockOffset::default(),
+                    screen: ping.screen.clone(),
+                    info,
+                });
+            }
+            self.arrange_nodes();
+            // after arranging so it goes by the same name it leaves by
+            if joined {
+                let name = self.nodes.iter()
+                    .find(|n| n.id == origin)
+                    .map(|n| n.display_name().to_string())
+                    .unwrap_or_default();
+                self.notify(NotificationEvent::Join, &name, format!("{} joined", name).as_str());
+            }
+            self.elect();
+            // another commander is in charge, so where we were is up to it now
+            if self.commander_id.as_ref() == Some(&origin) && !self.is_commander() {
+                self.restore_node = None;
+            }
+            self.save_state();
+
+            // back to where we were before we restarted
+            if self.restore_node.as_ref() == Some(&origin) && self.is_commander() {
+                log::info!("{} is back", ping.name);
+                self.send_to_loopback(Message {
+                    header: None,
+                    payload: Some(Payload::ActiveNodeChangedEvent(ActiveNodeChangedEvent {
+                        id: origin.clone(),
+                        target: InputTarget::All as i32,
+                    })),
+                });
+            }
+
+            // if we got the ping from the commander, make sure we're tracking state properly
+            if self.commander_id.as_ref() == Some(&origin) && !ping.active_node.is_empty() {
+                let or_active = |id: &String| if id.is_empty() { ping.active_node.clone() } else { id.clone() };
+                let mut changes = Vec::new();
+                let (mut pointer, mut scroll) = (self.target_id(InputTarget::Pointer), self.target_id(InputTarget::Scroll));
+                if self.active_node.as_ref() != Some(&ping.active_node) {
+                    changes.push((ping.active_node.clone(), InputTarget::All));
+                    pointer = Some(&ping.active_node);
+                    scroll = Some(&ping.active_node);
+                }
+                let ping_pointer = or_active(&ping.pointer_node);
+                if pointer != Some(&ping_pointer) {
+                    changes.push((ping_pointer, InputTarget::Pointer));
+                }
+                let ping_scroll = or_active(&ping.scroll_node);
+                if scroll != Some(&ping_scroll) {
+                    changes.push((ping_scroll, InputTarget::Scroll));
+                }
+
+                if self.mirror != ping.mirror_nodes {
+                    self.send_to_loopback(Message {
+                        header: Some(Header {
+                            from_id: origin.clone(),
+                            group: self.group.clone(),
+                            ..Header::default()
+                        }),
+                        payload: Some(Payload::MirrorEvent(MirrorEvent {
+                            ids: ping.mirror_nodes.clone(),
+                        })),
+                    });
+                }
+
+                // send events on loopback that look like active node changed events from the commander
+                for (id, input) in changes {
+                    self.send_to_loopback(Message {
+                        header: Some(Header {
+                            from_id: origin.clone(),
+                            group: self.group.clone(),
+                            ..Header::default()
+                        }),
+                        payload: Some(Payload::ActiveNodeChangedEvent(ActiveNodeChangedEvent {
+                            id,
+                            target: input as i32,
+                        })),
+                    });
+                }
+            }
+        } else {
+            let now = self.platform.clock.now();
+            let expired = self.nodes.iter()
+                .filter(|n| n.expired(now))
+                .map(|n| (n.id.clone(), n.display_name().to_string()))
+                .collect::<Vec<_>>();
+            for (id, name) in expired {
+                log::info!("Expiring {} ({})", name, id);
+                self.notify(NotificationEvent::Leave, &name, format!("{} left", name).as_str());
+            }
+            self.nodes.retain(|n| !n.expired(now));
+            self.elect();
+            self.save_state();
+
+            // the pointer and scroll wheel go back to the active node if theirs went away
+            let nodes = &self.nodes;
+            let known = |id: &String| nodes.iter().any(|n| &n.id == id);
+            self.pointer_node = self.pointer_node.take().filter(known);
+            self.scroll_node = self.scroll_node.take().filter(known);
+            self.mirror.retain(known);
+
+            if self.active_node.is_some() && self.active().is_none() {
+                // the active node went away.  Back to the commander, which may be a new one.
+                if let Some(commander_id) = self.commander_id.clone() {
+                    self.send_to_loopback(Message {
+                        header: None,
+                        payload: Some(Payload::ActiveNodeChangedEvent(ActiveNodeChangedEvent {
+                            id: commander_id,
+                            target: InputTarget::All as i32,
+                        })),
+                    });
+                }
+            } else if self.is_commander() {
+                self.check_idle(now);
+            }
+
+            self.send_to_input(Message {
+                header: None,
+                payload: Some(Payload::PingEvent(PingEvent::default())),
+            });
+
+            self.ping_sequence += 1;
+            for node in self.nodes.iter_mut().filter(|n| !n.local) {
+                log::debug!("{} {} {} up {}s", node.name, node.link, node.clock, node.info.uptime);
+                node.link.ping_sent(self.ping_sequence);
+            }
+
+            let my_node = self.my_node();
+            let uptime = self.platform.clock.now().duration_since(self.started).as_secs();
+            self.send_to_net(Message {
+                header: None,
+                payload: Some(Payload::PingEvent(PingEvent {
+                    commander: my_node.commander,
+                    active_node: if self.is_commander() {
+                        if let Some(n) = self.active() {
+                            n.id.clone()
+                        } else {
+                            "".to_string()
+                        }
+                    } else {
+                        "".to_string()
+                    },
+                    sequence: self.ping_sequence,
+                    time: Some(self.platform.clock.system_time().into()),
+                    name: my_node.name.clone(),
+                    screen: my_node.screen.clone(),
+                    priority: my_node.priority,
+                    claim: my_node.claim,
+                    pointer_node: if self.is_commander() { self.pointer_node.clone().unwrap_or_default() } else { String::new() },
+                    scroll_node: if self.is_commander() { self.scroll_node.clone().unwrap_or_default() } else { String::new() },
+                    mirror_nodes: if self.is_commander() { self.mirror.clone() } else { Vec::new() },
+                    info: Some(NodeInfo {
+                        uptime,
+                        ..my_node.info.clone()
+                    }),
+                })),
+            }, "");
+        }
+    }
+
+    /// Take the keyboard back from a node that hasn't been used for its idle timeout
+    fn check_idle(&mut self, now: Instant) {
+        let active = match self.active() {
+            Some(active) if !active.local => active,
+            _ => return,
+        };
+        let entry = match self.node_order.iter().find(|entry| active.is_called(&entry.name)) {
+            Some(entry) => entry,
+            None => return,
+        };
+        let timeout = match entry.idle_timeout {
+            Some(minutes) if minutes > 0 => Duration::from_secs(minutes * 60),
+            _ => return,
+        };
+        if now.duration_since(self.last_input) < timeout {
+            return;
+        }
+
+        log::info!("{} has been idle for {:?}", active.display_name(), timeout);
+        if !entry.idle_command.is_empty() {
+            self.send_to_net(Message {
+                header: None,
+                payload: Some(Payload::RunCommandEvent(RunCommandEvent {
+                    name: entry.idle_command.clone(),
+                })),
+            }, active.id.as_str());
+        }
+        let id = self.my_node().id.clone();
+        self.last_input = now;
+        self.send_to_loopback(Message {
+            header: None,
+            payload: Some(Payload::ActiveNodeChangedEvent(ActiveNodeChangedEvent {
+                id,
+                target: InputTarget::All as i32,
+            })),
+        });
+    }
+
+    fn handle_pong(&mut self, origin: String, pong: &PongEvent) {
+        let now = self.platform.clock.system_time();
+        let time = |t: &Option<Timestamp>| t.clone().and_then(|t| SystemTime::try_from(t).ok());
+        let ping_time = match time(&pong.ping_time) {
+            Some(ping_time) => ping_time,
+            None => return,
+        };
+        if let Some(node) = self.nodes.iter_mut().find(|n| n.id == origin) {
+            // a late or repeated pong would skew the clock offset
+            if !node.link.awaiting(pong.sequence) {
+                log::debug!("Ignoring pong {} from {}", pong.sequence, node.name);
+                return;
+            }
+            let rtt = match (time(&pong.receive_time), time(&pong.time)) {
+                (Some(receive_time), Some(pong_time)) =>
+                    node.clock.sample(ping_time, receive_time, pong_time, now),
+                _ => now.duration_since(ping_time).unwrap_or_default(),
+            };
+            let was_slow = node.link.is_slow();
+            node.link.pong_received(pong.sequence, rtt);
+            log::trace!("Pong from {} after {:?} ({})", node.name, rtt, node.clock);
+            if node.link.is_slow() && !was_slow {
+                log::warn!("Link to {} is too slow for comfortable input ({})", node.name, node.link);
+            }
+        }
+    }
+
+    /// How long ago the message was generated, corrected for the origin's clock offset.  None until
+    /// a pong tells us the offset.
+    fn elapsed_time(&self, message: &Message, origin: &str) -> Option<Duration> {
+        let clock_offset = self.nodes.iter()
+            .find(|n| n.id == origin)
+            .and_then(|n| n.clock.offset())?;
+        message.elapsed_time_with_offset(self.platform.clock.system_time(), clock_offset)
+    }
+
+    fn handle_claim(&mut self, origin: String, claim: &ClaimEvent) {
+        if let Some(node) = self.nodes.iter_mut().find(|n| n.id == origin) {
+            node.commander = true;
+            node.claim = node.claim.max(claim.claim);
+            self.elect();
+        } else {
+            log::debug!("Claim from unknown node {}", origin);
+        }
+    }
+
+    fn handle_run_command(&self, from_net: bool, origin: String, run_command: &RunCommandEvent) {
+        if !from_net {
+            match self.active() {
+                Some(active_node) if !active_node.local => {
+                    self.send_to_net(Message {
+                        header: None,
+                        payload: Some(Payload::RunCommandEvent(run_command.clone())),
+                    }, active_node.id.as_str());
+                    return;
+                }
+                _ => {}
+            }
+        } else if self.commander_id.as_ref() != Some(&origin) {
+            // only keeps honest nodes in line.  The from id isn't authenticated, so anyone can claim to be the commander.
+            log::warn!("Ignoring command {} from {} which isn't the commander", run_command.name, origin);
+            return;
+        }
+
+        match self.commands.get(&run_command.name) {
+            Some(command) => command::run(command),
+            None => log::warn!("No command called {}", run_command.name),
+        }
+    }
+
+    /// A request from rkvm2ctl
+    fn handle_control(&mut self, header: Option<&Header>, request: &ControlRequest) {
+        let header = match header {
+            Some(header) if !header.id.is_empty() => header,
+            _ => {
+                log::warn!("Ignoring control request without an id {:?}", request);
+                return;
+            }
+        };
+        let id = header.id.clone();
+        if let Some(HeaderType::Request(request_header)) = &header.header_type {
+            if request_header.cancel {
+                self.watchers.retain(|w| w != &id);
+                return;
+            }
+        }
+
+        log::debug!("Control request {:?}", request);
+        let next_node_index = match request.command() {
+            ControlCommand::Status => None,
+            ControlCommand::Switch => match self.nodes.iter().position(|n| n.is_called(&request.node)) {
+                Some(index) => Some(index),
+                None => {
+                    self.respond(id, ResponseCode::Error, format!("No node called {}", request.node));
+                    return;
+                }
+            },
+            ControlCommand::Next => Some(self.active_index().map_or(0, |index| (index + 1) % self.nodes.len())),
+            ControlCommand::SendClipboard => {
+                let text = self.platform.clipboard.get_text();
+                if !self.share_clipboard(text) {
+                    self.respond(id, ResponseCode::Error, "Failed to get clipboard text".to_string());
+                    return;
+                }
+                None
+            }
+            ControlCommand::Notify => {
+                self.handle_notify(false, String::new(), &NotifyEvent { text: request.text.clone() }, false);
+                None
+            }
+            ControlCommand::Watch => {
+                if !self.watchers.contains(&id) {
+                    self.watchers.push(id.clone());
+                }
+                self.watched = Some(watched_status(self.status()));
+                self.respond(id, ResponseCode::Update, String::new());
+                return;
+            }
+        };
+        if let Some(index) = next_node_index {
+            let id = self.nodes[index].id.clone();
+            self.handle_active_node_changed(false, &ActiveNodeChangedEvent { id, target: InputTarget::All as i32 });
+        }
+        self.respond(id, ResponseCode::Ok, String::new());
+    }
+
+    /// Answer a control request with the status
+    fn respond(&self, request_id: String, code: ResponseCode, message: String) {
+        let response = Message {
+            header: Some(Header {
+                from_id: self.my_node().id.clone(),
+                header_type: Some(HeaderType::Response(ResponseHeader {
+                    code: code as i32,
+                    message,
+                    request_id,
+                })),
+                ..Header::default()
+            }),
+            payload: Some(Payload::StatusEvent(self.status())),
+        };
+        if let Err(e) = self.control_sender.send(response) {
+            log::warn!("Failed to send control response {}", e);
+        }
+    }
+
+    /// The nodes and where input goes, for rkvm2ctl
+    fn status(&self) -> StatusEvent {
+        let now = self.platform.clock.now();
+        let uptime = now.duration_since(self.started).as_secs();
+        let millis = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        StatusEvent {
+            id: self.my_node().id.clone(),
+            commander: self.commander_id.clone().unwrap_or_default(),
+            active_node: self.target_id(InputTarget::Keyboard).cloned().unwrap_or_default(),
+            pointer_node: self.target_id(InputTarget::Pointer).cloned().unwrap_or_default(),
+            scroll_node: self.target_id(InputTarget::Scroll).cloned().unwrap_or_default(),
+            mirror_nodes: self.mirror.clone(),
+            nodes: self.nodes.iter()
+                .map(|n| NodeStatus {
+                    id: n.id.clone(),
+                    name: n.display_name().to_string(),
+                    local: n.local,
+                    commander: n.commander,
+                    last_heard: if n.local { 0 } else { now.duration_since(n.last_heard_from).as_millis() as u64 },
+                    rtt: n.link.rtt().map_or(0.0, millis),
+                    jitter: millis(n.link.jitter()),
+                    loss: n.link.loss(),
+                    slow: n.link.is_slow(),
+                    info: Some(if n.local { NodeInfo { uptime, ..n.info.clone() } } else { n.info.clone() }),
+                })
+                .collect(),
+        }
+    }
+
+    /// Tell the watchers if the nodes or where input goes changed
+    fn update_watchers(&mut self) {
+        if self.watchers.is_empty() {
+            return;
+        }
+        let watched = watched_status(self.status());
+        if self.watched.as_ref() == Some(&watched) {
+            return;
+        }
+        self.watched = Some(watched);
+        for id in self.watchers.clone() {
+            self.respond(id, ResponseCode::Update, String::new());
+        }
+    }
+
+    /// Get a notification in front of the user.  The commander shows notifications from nodes
+    /// they aren't looking at.
+    fn handle_notify(&mut self, from_net: bool, origin: String, notify: &NotifyEvent, shown_here: bool) {
+        if from_net {
+            if !self.is_commander() {
+                log::debug!("Ignoring notification from {} since we're not the commander", origin);
+                return;
+            }
+            let name = self.nodes.iter()
+                .find(|n| n.id == origin)
+                .map_or(origin.as_str(), |n| n.display_name())
+                .to_string();
+            self.notify(NotificationEvent::Forwarded, &name, &notify.text);
+        } else if self.is_commander() || self.active().is_some_and(|n| n.local) {
+            // the user can see this node
+            if !shown_here {
+                let name = self.my_node().display_name().to_string();
+                self.notify(NotificationEvent::Forwarded, &name, &notify.text);
+            }
+        } else if let Some(commander_id) = &self.commander_id {
+            self.send_to_net(Message {
+                header: None,
+                payload: Some(Payload::NotifyEvent(notify.clone())),
+            }, commander_id);
+        } else {
+            log::debug!("No commander to show {:?}", notify.text);
+        }
+    }
+
+    fn handle_clipboard(&mut self, clipboard: &ClipboardEvent) {
+        let text = String::from_utf8_lossy(&clipboard.data).to_string();
+        log::debug!("Got clip text\n{}", text);
+        if let Err(e) = self.platform.clipboard.set_text(text) {
+            log::warn!("Failed to set clipboard text {}", e);
+        }
+    }
+
+    /// Show what happened the way it's configured.  The node is the one it's about.
+    fn notify(&mut self, event: NotificationEvent, node: &str, message: &str) {
+        let style = self.notifications.style(event);
+        let forwarded = event == NotificationEvent::Forwarded;
+        let enabled = style.enabled.unwrap_or(!matches!(event, NotificationEvent::Join | NotificationEvent::Leave));
+        if !enabled {
+            log::trace!("Not showing {:?} notification {:?}", event, message);
+            return;
+        }
+        let summary = style.summary.unwrap_or_else(|| if forwarded { "{node}" } else { "RKVM" }.to_string());
+        let body = style.body.unwrap_or_else(|| "{message}".to_string());
+        let fill = |template: String| template.replace("{node}", node).replace("{message}", message);
+        self.platform.notifier.notify(Notification {
+            event,
+            summary: fill(summary),
+            body: fill(body),
+            urgency: style.urgency,
+            timeout: style.timeout,
+            icon: style.icon.unwrap_or_default(),
+            // notifications from other nodes are worth keeping around
+            replace: !forwarded,
+        });
+    }
+}
+
+/// Mouse buttons come in as keys.  Their codes don't overlap.
+fn is_button(code: i32) -> bool {
+    code != 0 && Button::from_i32(code).is_some()
+}
+
+/// The status without the numbers that change with every ping
+fn watched_status(status: StatusEvent) -> StatusEvent {
+    StatusEvent {
+        nodes: status.nodes.into_iter()
+            .map(|n| NodeStatus {
+                id: n.id,
+                name: n.name,
+                local: n.local,
+                commander: n.commander,
+                ..NodeStatus::default()
+            })
+            .collect(),
+        ..status
+    }
+}
+
+fn key_event_message(key_event: KeyEvent) -> Message {
+    Message {
+        header: None,
+        payload: Some(Payload::InputEvent(InputEvent {
+            input_event_type: Some(InputEventType::Key(key_event)),
+        })),
+    }
+}
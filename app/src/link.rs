@@ -0,0 +1,141 @@
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, SystemTime};
+
+/// A link is too slow for comfortable input if the round trip takes longer than this
+const SLOW_LINK_RTT: Duration = Duration::from_millis(50);
+/// ...or if the round trip time wobbles around more than this
+const SLOW_LINK_JITTER: Duration = Duration::from_millis(20);
+/// ...or if we lose more than this fraction of pings
+const SLOW_LINK_LOSS: f64 = 0.1;
+/// Weight of a new sample in the smoothed values.  Same as tcp's srtt.
+const SAMPLE_WEIGHT: f64 = 0.125;
+/// How many clock offset samples to pick the best one from.  Same as ntp's clock filter.
+const CLOCK_SAMPLES: usize = 8;
+
+/// Round trip statistics for a node measured using ping/pong
+#[derive(Debug, Default)]
+pub(crate) struct LinkStats {
+    rtt: Option<Duration>,
+    jitter: Duration,
+    loss: f64,
+    awaiting_pong: Option<u64>,
+}
+
+impl LinkStats {
+    /// We broadcast a ping with this sequence.  If we never heard back about the last one, it's lost.
+    pub(crate) fn ping_sent(&mut self, sequence: u64) {
+        let lost = self.awaiting_pong.replace(sequence).is_some();
+        self.loss = smooth(self.loss, if lost { 1.0 } else { 0.0 });
+    }
+
+    /// True if the sequence is the ping we're waiting on a pong for
+    pub(crate) fn awaiting(&self, sequence: u64) -> bool {
+        self.awaiting_pong == Some(sequence)
+    }
+
+    /// We got a pong for the given ping sequence after the given round trip time
+    pub(crate) fn pong_received(&mut self, sequence: u64, rtt: Duration) {
+        if !self.awaiting(sequence) {
+            log::debug!("Ignoring pong {} (expected {:?})", sequence, self.awaiting_pong);
+            return;
+        }
+        self.awaiting_pong = None;
+
+        match self.rtt {
+            None => {
+                self.rtt = Some(rtt);
+            }
+            Some(previous) => {
+                let deviation = rtt.abs_diff(previous);
+                self.jitter = Duration::from_secs_f64(
+                    smooth(self.jitter.as_secs_f64(), deviation.as_secs_f64()));
+                self.rtt = Some(Duration::from_secs_f64(
+                    smooth(previous.as_secs_f64(), rtt.as_secs_f64())));
+            }
+        }
+    }
+
+    /// Smoothed round trip time, once there's been a pong
+    pub(crate) fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    pub(crate) fn jitter(&self) -> Duration {
+        self.jitter
+    }
+
+    /// Smoothed fraction of pings lost
+    pub(crate) fn loss(&self) -> f64 {
+        self.loss
+    }
+
+    /// True if input to this node is going to feel bad
+    pub(crate) fn is_slow(&self) -> bool {
+        self.loss > SLOW_LINK_LOSS
+            || self.jitter > SLOW_LINK_JITTER
+            || self.rtt.is_some_and(|rtt| rtt > SLOW_LINK_RTT)
+    }
+}
+
+impl Display for LinkStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.rtt {
+            None => write!(f, "rtt ? ")?,
+            Some(rtt) => write!(f, "rtt {:.1}ms ", rtt.as_secs_f64() * 1000.0)?,
+        }
+        write!(f, "jitter {:.1}ms loss {:.0}%", self.jitter.as_secs_f64() * 1000.0, self.loss * 100.0)
+    }
+}
+
+/// Estimate of how far a node's clock is ahead of ours, ntp style.
+#[derive(Debug, Default)]
+pub(crate) struct ClockOffset {
+    /// (offset in nanos, round trip delay) of the most recent exchanges
+    samples: VecDeque<(i64, Duration)>,
+}
+
+impl ClockOffset {
+    /// Add a sample from a ping/pong exchange.  t0 is when we sent the ping, t1 when the node
+    /// received it, t2 when the node sent the pong and t3 when we received it.
+    /// Returns the round trip delay, not counting the time the node took to answer.
+    pub(crate) fn sample(&mut self, t0: SystemTime, t1: SystemTime, t2: SystemTime, t3: SystemTime) -> Duration {
+        let offset = (nanos_between(t0, t1) + nanos_between(t3, t2)) / 2;
+        let delay = nanos_between(t0, t3) - nanos_between(t1, t2);
+        let delay = Duration::from_nanos(delay.max(0) as u64);
+        if self.samples.len() == CLOCK_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((offset, delay));
+        delay
+    }
+
+    /// Nanoseconds the node's clock is ahead of ours.  The sample with the shortest round trip
+    /// wins because it had the least room for asymmetric delays to mess it up.
+    pub(crate) fn offset(&self) -> Option<i64> {
+        self.samples.iter()
+            .min_by_key(|(_, delay)| *delay)
+            .map(|(offset, _)| *offset)
+    }
+}
+
+impl Display for ClockOffset {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.offset() {
+            None => write!(f, "offset ?"),
+            Some(offset) => write!(f, "offset {:+.1}ms", offset as f64 / 1_000_000.0),
+        }
+    }
+}
+
+/// Nanoseconds from a to b, negative if b is before a
+fn nanos_between(a: SystemTime, b: SystemTime) -> i64 {
+    match b.duration_since(a) {
+        Ok(duration) => duration.as_nanos() as i64,
+        Err(e) => -(e.duration().as_nanos() as i64),
+    }
+}
+
+fn smooth(current: f64, sample: f64) -> f64 {
+    current + (sample - current) * SAMPLE_WEIGHT
+}
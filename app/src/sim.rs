@@ -0,0 +1,1075 @@
+use std::collections::{BTreeMap, HashSet};
+use std::io;
+use std::io::Error;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use num_traits::ToPrimitive;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+
+use rkvm2_config::{Binding, Config, NodeEntry};
+use rkvm2_proto::{Button, Header, InputEvent, Key, KeyEvent, Message, MouseMoveEvent, NodeInfo, RunCommandEvent};
+use rkvm2_proto::input_event::InputEventType;
+use rkvm2_proto::message::Payload;
+
+use crate::{App, Platform};
+use crate::clock::SystemClock;
+use crate::conn::{Connection, Connector, MessageSink, MessageStream};
+use crate::desktop::{Clipboard, Notification, Notifier};
+use crate::state::{SavedState, StateStore};
+
+/// How long a reordered message is held back so the ones behind it can overtake
+const REORDER_HOLD: Duration = Duration::from_millis(50);
+
+/// How badly the simulated network behaves.  Probabilities are 0..=1
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Impairment {
+    /// Chance a message is dropped
+    pub(crate) loss: f64,
+    /// Fixed delay added to every message
+    pub(crate) delay: Duration,
+    /// Random delay of up to this much added to every message
+    pub(crate) jitter: Duration,
+    /// Chance a message is delivered twice
+    pub(crate) duplicate: f64,
+    /// Chance a message is held back long enough for later messages to overtake it
+    pub(crate) reorder: f64,
+}
+
+/// An in-process broadcast network.  Like the udp broadcast address, everything sent is
+/// delivered to every connected endpoint, including the sender.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SimNetwork {
+    endpoints: Arc<Mutex<Vec<UnboundedSender<Message>>>>,
+}
+
+/// Connects to a [SimNetwork] through the given impairment
+#[derive(Debug)]
+pub(crate) struct SimConnector {
+    network: SimNetwork,
+    impairment: Impairment,
+    seed: u64,
+}
+impl SimConnector {
+    pub(crate) fn new(network: SimNetwork, impairment: Impairment, seed: u64) -> Self {
+        Self {
+            network,
+            impairment,
+            seed,
+        }
+    }
+}
+
+#[async_trait]
+impl Connector for SimConnector {
+    type SinkType = SimSink;
+    type StreamType = SimStream;
+    async fn connect(&self) -> io::Result<(Self::SinkType, Self::StreamType)> {
+        let (sender, receiver) = unbounded_channel();
+        self.network.endpoints.lock().unwrap().push(sender);
+        Ok((
+            SimSink {
+                network: self.network.clone(),
+                impairment: self.impairment.clone(),
+                rng: StdRng::seed_from_u64(self.seed),
+            },
+            SimStream { receiver },
+        ))
+    }
+}
+
+pub(crate) struct SimSink {
+    network: SimNetwork,
+    impairment: Impairment,
+    rng: StdRng,
+}
+impl SimSink {
+    fn delay(&mut self) -> Duration {
+        let mut delay = self.impairment.delay;
+        if !self.impairment.jitter.is_zero() {
+            delay += self.rng.gen_range(Duration::ZERO..=self.impairment.jitter);
+        }
+        if self.rng.gen_bool(self.impairment.reorder) {
+            delay += REORDER_HOLD;
+        }
+        delay
+    }
+}
+#[async_trait]
+impl MessageSink for SimSink {
+    async fn send(&mut self, message: Message) -> Result<(), Error> {
+        let endpoints = self.network.endpoints.lock().unwrap().clone();
+        for endpoint in endpoints {
+            if self.rng.gen_bool(self.impairment.loss) {
+                continue;
+            }
+            let copies = if self.rng.gen_bool(self.impairment.duplicate) { 2 } else { 1 };
+            for _ in 0..copies {
+                let delay = self.delay();
+                let message = message.clone();
+                if delay.is_zero() {
+                    let _ = endpoint.send(message);
+                } else {
+                    let endpoint = endpoint.clone();
+                    tokio::spawn(async move {
+                        sleep(delay).await;
+                        let _ = endpoint.send(message);
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+pub(crate) struct SimStream {
+    receiver: UnboundedReceiver<Message>,
+}
+#[async_trait]
+impl MessageStream for SimStream {
+    async fn next(&mut self) -> Option<Result<Message, Error>> {
+        self.receiver.recv().await.map(Ok)
+    }
+}
+
+/// A clipboard nobody else can see
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SimClipboard {
+    pub(crate) text: Arc<Mutex<String>>,
+}
+impl Clipboard for SimClipboard {
+    fn get_text(&mut self) -> Result<String, String> {
+        Ok(self.text.lock().unwrap().clone())
+    }
+
+    fn set_text(&mut self, text: String) -> Result<(), String> {
+        *self.text.lock().unwrap() = text;
+        Ok(())
+    }
+}
+
+/// Keeps notifications instead of showing them
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SimNotifier {
+    pub(crate) notifications: Arc<Mutex<Vec<Notification>>>,
+}
+impl SimNotifier {
+    /// The bodies of the notifications since the last call
+    pub(crate) fn take(&self) -> Vec<String> {
+        self.take_all().into_iter().map(|n| n.body).collect()
+    }
+
+    /// The notifications since the last call
+    pub(crate) fn take_all(&self) -> Vec<Notification> {
+        std::mem::take(&mut self.notifications.lock().unwrap())
+    }
+}
+impl Notifier for SimNotifier {
+    fn notify(&mut self, notification: Notification) {
+        self.notifications.lock().unwrap().push(notification);
+    }
+}
+
+/// Keeps the saved state in memory so it survives a restart of the app, not the process
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SimStateStore {
+    pub(crate) state: Arc<Mutex<Option<SavedState>>>,
+}
+impl StateStore for SimStateStore {
+    fn load(&mut self) -> Option<SavedState> {
+        self.state.lock().unwrap().clone()
+    }
+
+    fn save(&mut self, state: &SavedState) {
+        *self.state.lock().unwrap() = Some(state.clone());
+    }
+}
+
+/// Roughly what we see on bad wifi
+fn wifi() -> Impairment {
+    Impairment {
+        loss: 0.2,
+        delay: Duration::from_millis(5),
+        jitter: Duration::from_millis(40),
+        duplicate: 0.1,
+        reorder: 0.1,
+    }
+}
+
+fn config(name: &str, commander: bool) -> Config {
+    Config {
+        name: name.to_string(),
+        switch_keys: vec![Key::RightCtrl, Key::RightAlt],
+        commander_keys: vec![Key::RightCtrl, Key::Home],
+        left_keys: vec![Key::LeftCtrl, Key::LeftAlt, Key::Left],
+        right_keys: vec![Key::LeftCtrl, Key::LeftAlt, Key::Right],
+        up_keys: vec![Key::LeftCtrl, Key::LeftAlt, Key::Up],
+        down_keys: vec![Key::LeftCtrl, Key::LeftAlt, Key::Down],
+        sequence_timeout: 1000,
+        double_tap_timeout: 300,
+        commander,
+        ..Config::default()
+    }
+}
+
+/// An app hooked up to the sim network
+struct SimNode {
+    id: String,
+    app: Arc<Mutex<App>>,
+    /// Messages from "inputd"
+    sender: UnboundedSender<Message>,
+    /// Messages the app wrote to its input device
+    input: UnboundedReceiver<Message>,
+    /// What the app told the user
+    notifier: SimNotifier,
+    /// Keys currently held down on the input device
+    pressed: HashSet<i32>,
+    /// Every key event written to the input device
+    key_log: Vec<KeyEvent>,
+    /// Every pointer motion and scroll event written to the input device
+    motion_log: Vec<InputEventType>,
+    /// Last pointer position written to the input device
+    position: Option<(i32, i32)>,
+    /// The app's message loop
+    handle: JoinHandle<()>,
+}
+impl SimNode {
+    fn spawn(name: &str, commander: bool, network: &SimNetwork, impairment: Impairment, seed: u64) -> Self {
+        Self::spawn_with(config(name, commander), network, impairment, seed)
+    }
+
+    fn spawn_with(config: Config, network: &SimNetwork, impairment: Impairment, seed: u64) -> Self {
+        let (message_sender, mut message_receiver) = unbounded_channel();
+        let (input_sender, input) = unbounded_channel();
+        let net_sender = Connection::open(
+            SimConnector::new(network.clone(), impairment, seed), message_sender.clone());
+
+        let id = format!("{}-{}", config.name, seed);
+        let notifier = SimNotifier::default();
+        let platform = Platform {
+            clock: Box::new(SystemClock),
+            clipboard: Box::new(SimClipboard::default()),
+            notifier: Box::new(notifier.clone()),
+            state: Box::new(SimStateStore::default()),
+            info: NodeInfo::default(),
+        };
+        // nothing asks the sim nodes for their status
+        let (control_sender, _) = unbounded_channel();
+        let app = App::new(id.clone(), config, platform, input_sender, net_sender, message_sender.clone(), control_sender);
+        app.start_pinging();
+        let app = Arc::new(Mutex::new(app));
+        let loop_app = app.clone();
+        let handle = tokio::spawn(async move {
+            while let Some(message) = message_receiver.recv().await {
+                loop_app.lock().unwrap().handle_message(message);
+            }
+        });
+
+        Self {
+            id,
+            app,
+            sender: message_sender,
+            input,
+            notifier,
+            pressed: HashSet::new(),
+            key_log: Vec::new(),
+            motion_log: Vec::new(),
+            position: None,
+            handle,
+        }
+    }
+
+    /// Stop handling messages, like the node was switched off
+    fn stop(&self) {
+        self.handle.abort();
+    }
+
+    fn active_node(&self) -> Option<String> {
+        self.app.lock().unwrap().active().map(|n| n.id.clone())
+    }
+
+    fn input_event(&self, input_event_type: InputEventType) {
+        self.sender.send(Message {
+            header: None,
+            payload: Some(Payload::InputEvent(InputEvent {
+                input_event_type: Some(input_event_type),
+            })),
+        }).unwrap();
+    }
+
+    fn key(&self, key: Key, down: bool) {
+        self.input_event(InputEventType::Key(KeyEvent {
+            key: key.to_i32().unwrap(),
+            down,
+        }));
+    }
+
+    fn button(&self, button: Button, down: bool) {
+        self.input_event(InputEventType::Key(KeyEvent {
+            key: button.to_i32().unwrap(),
+            down,
+        }));
+    }
+
+    async fn click(&self, button: Button) {
+        self.button(button, true);
+        sleep(Duration::from_millis(20)).await;
+        self.button(button, false);
+        sleep(Duration::from_millis(20)).await;
+    }
+
+    async fn move_pointer(&self, dx: i32, dy: i32) {
+        if dx != 0 {
+            self.input_event(InputEventType::X(MouseMoveEvent { delta: dx }));
+        }
+        if dy != 0 {
+            self.input_event(InputEventType::Y(MouseMoveEvent { delta: dy }));
+        }
+        sleep(Duration::from_millis(20)).await;
+    }
+
+    /// Press the keys in order, then release them in reverse
+    async fn chord(&self, keys: &[Key]) {
+        for key in keys {
+            self.key(*key, true);
+            sleep(Duration::from_millis(20)).await;
+        }
+        for key in keys.iter().rev() {
+            self.key(*key, false);
+            sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    async fn type_text(&self, keys: &[Key]) {
+        for key in keys {
+            self.chord(&[*key]).await;
+        }
+    }
+
+    /// Replay everything written to the input device so far
+    fn replay_input(&mut self) {
+        while let Ok(message) = self.input.try_recv() {
+            match message.payload {
+                Some(Payload::InputEvent(InputEvent {
+                    input_event_type: Some(InputEventType::Key(key_event))
+                })) => {
+                    if key_event.down {
+                        self.pressed.insert(key_event.key);
+                    } else {
+                        self.pressed.remove(&key_event.key);
+                    }
+                    self.key_log.push(key_event);
+                }
+                Some(Payload::InputEvent(InputEvent {
+                    input_event_type: Some(InputEventType::Position(position))
+                })) => {
+                    self.position = Some((position.x, position.y));
+                }
+                Some(Payload::InputEvent(InputEvent {
+                    input_event_type: Some(motion)
+                })) => {
+                    self.motion_log.push(motion);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn pressed(&mut self) -> &HashSet<i32> {
+        self.replay_input();
+        &self.pressed
+    }
+
+    /// The key events written to the input device since the last call
+    fn take_key_log(&mut self) -> Vec<KeyEvent> {
+        self.replay_input();
+        std::mem::take(&mut self.key_log)
+    }
+
+    /// The motion and scroll events written to the input device since the last call
+    fn take_motion_log(&mut self) -> Vec<InputEventType> {
+        self.replay_input();
+        std::mem::take(&mut self.motion_log)
+    }
+
+    fn position(&mut self) -> Option<(i32, i32)> {
+        self.replay_input();
+        self.position
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn keys_are_released_under_bad_network() {
+    let network = SimNetwork::default();
+    let mut commander = SimNode::spawn("commander", true, &network, wifi(), 1);
+    let mut remote = SimNode::spawn("remote", false, &network, wifi(), 2);
+    sleep(Duration::from_secs(10)).await;
+
+    for _ in 0..5 {
+        commander.chord(&[Key::RightCtrl, Key::RightAlt]).await;
+        sleep(Duration::from_millis(200)).await;
+        assert_eq!(Some(remote.id.clone()), commander.active_node());
+
+        commander.type_text(&[Key::H, Key::E, Key::L, Key::L, Key::O]).await;
+        commander.chord(&[Key::RightCtrl, Key::Home]).await;
+        sleep(Duration::from_millis(200)).await;
+        assert_eq!(Some(commander.id.clone()), commander.active_node());
+    }
+
+    // give the commander's pings a chance to straighten things out
+    sleep(Duration::from_secs(10)).await;
+    assert!(commander.pressed().is_empty(), "Stuck keys on commander {:?}", commander.pressed());
+    assert!(remote.pressed().is_empty(), "Stuck keys on remote {:?}", remote.pressed());
+}
+
+#[tokio::test(start_paused = true)]
+async fn nodes_agree_on_active_node_under_bad_network() {
+    let network = SimNetwork::default();
+    let commander = SimNode::spawn("commander", true, &network, wifi(), 1);
+    let remotes = vec![
+        SimNode::spawn("remote1", false, &network, wifi(), 2),
+        SimNode::spawn("remote2", false, &network, wifi(), 3),
+    ];
+    sleep(Duration::from_secs(10)).await;
+
+    for switches in 1..=7 {
+        commander.chord(&[Key::RightCtrl, Key::RightAlt]).await;
+        sleep(Duration::from_millis(100 * switches)).await;
+    }
+
+    sleep(Duration::from_secs(10)).await;
+    let active_node = commander.active_node();
+    assert!(active_node.is_some());
+    for remote in &remotes {
+        assert_eq!(active_node, remote.active_node());
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn nodes_with_the_same_name_see_each_other() {
+    let network = SimNetwork::default();
+    let commander = SimNode::spawn("twin", true, &network, Impairment::default(), 1);
+    let remote = SimNode::spawn("twin", false, &network, Impairment::default(), 2);
+    sleep(Duration::from_secs(4)).await;
+
+    commander.chord(&[Key::RightCtrl, Key::RightAlt]).await;
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(Some(remote.id.clone()), commander.active_node());
+    assert_eq!(Some(remote.id.clone()), remote.active_node());
+}
+
+#[tokio::test(start_paused = true)]
+async fn groups_ignore_each_other() {
+    let network = SimNetwork::default();
+    let group = |name: &str, commander: bool, group: &str| Config {
+        group: group.to_string(),
+        ..config(name, commander)
+    };
+    let red_commander = SimNode::spawn_with(group("red-commander", true, "red"), &network, Impairment::default(), 1);
+    let red_remote = SimNode::spawn_with(group("red-remote", false, "red"), &network, Impairment::default(), 2);
+    let blue_commander = SimNode::spawn_with(group("blue-commander", true, "blue"), &network, Impairment::default(), 3);
+    sleep(Duration::from_secs(4)).await;
+
+    // both commanders would cycle through each other if they could see each other
+    red_commander.chord(&[Key::RightCtrl, Key::RightAlt]).await;
+    red_commander.chord(&[Key::RightCtrl, Key::RightAlt]).await;
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(Some(red_commander.id.clone()), red_commander.active_node());
+    assert_eq!(Some(red_commander.id.clone()), red_remote.active_node());
+    assert_eq!(Some(blue_commander.id.clone()), blue_commander.active_node());
+}
+
+#[tokio::test(start_paused = true)]
+async fn pointer_switches_at_screen_edges() {
+    let network = SimNetwork::default();
+    let mut commander = SimNode::spawn_with(Config {
+        screen_width: 100,
+        screen_height: 100,
+        right: "remote".to_string(),
+        ..config("commander", true)
+    }, &network, Impairment::default(), 1);
+    let mut remote = SimNode::spawn_with(Config {
+        screen_width: 200,
+        screen_height: 400,
+        ..config("remote", false)
+    }, &network, Impairment::default(), 2);
+    sleep(Duration::from_secs(4)).await;
+
+    // starts in the middle of the commander.  Wander around without leaving.
+    commander.move_pointer(40, -30).await;
+    commander.move_pointer(-80, 0).await;
+    assert_eq!(Some(commander.id.clone()), commander.active_node());
+
+    // off the left edge, there's nothing there
+    commander.move_pointer(-100, 0).await;
+    assert_eq!(Some(commander.id.clone()), commander.active_node());
+
+    // all the way across and off the right edge
+    commander.move_pointer(100, 0).await;
+    assert_eq!(Some(remote.id.clone()), commander.active_node());
+    assert_eq!(Some(remote.id.clone()), remote.active_node());
+    assert_eq!(Some((0, 80)), remote.position());
+
+    // and back again from the remote's left edge even though only the commander has a layout
+    commander.move_pointer(0, 200).await;
+    commander.move_pointer(-1, 0).await;
+    assert_eq!(Some(commander.id.clone()), commander.active_node());
+    assert_eq!(Some((99, 70)), commander.position());
+}
+
+#[tokio::test(start_paused = true)]
+async fn directional_keys_follow_the_layout() {
+    let network = SimNetwork::default();
+    // left - commander - right, with below under the commander
+    let commander = SimNode::spawn_with(Config {
+        left: "left".to_string(),
+        right: "right".to_string(),
+        wrap_around: true,
+        ..config("commander", true)
+    }, &network, Impairment::default(), 1);
+    let left = SimNode::spawn("left", false, &network, Impairment::default(), 2);
+    let right = SimNode::spawn("right", false, &network, Impairment::default(), 3);
+    let below = SimNode::spawn_with(Config {
+        above: "commander".to_string(),
+        ..config("below", false)
+    }, &network, Impairment::default(), 4);
+    sleep(Duration::from_secs(4)).await;
+
+    let chord = |arrow| [Key::LeftCtrl, Key::LeftAlt, arrow];
+    for (arrow, expected) in [
+        (Key::Left, &left),
+        (Key::Right, &commander),
+        (Key::Right, &right),
+        // wraps around to the left end
+        (Key::Right, &left),
+        (Key::Right, &commander),
+        (Key::Down, &below),
+        (Key::Up, &commander),
+        // wraps around to the bottom
+        (Key::Up, &below),
+    ] {
+        commander.chord(&chord(arrow)).await;
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(Some(expected.id.clone()), commander.active_node(), "After {:?}", arrow);
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn bindings_jump_to_nodes_by_name() {
+    let network = SimNetwork::default();
+    let commander = SimNode::spawn_with(Config {
+        bindings: BTreeMap::from([
+            ("RightCtrl+1".to_string(), Binding { switch_to: Some("build-box".to_string()), ..Binding::default() }),
+            ("RightCtrl+2".to_string(), Binding { switch_to: Some("laptop".to_string()), ..Binding::default() }),
+            ("RightCtrl+3".to_string(), Binding { switch_to: Some("nowhere".to_string()), ..Binding::default() }),
+        ]),
+        ..config("commander", true)
+    }, &network, Impairment::default(), 1);
+    let build_box = SimNode::spawn("build-box", false, &network, Impairment::default(), 2);
+    let laptop = SimNode::spawn("laptop", false, &network, Impairment::default(), 3);
+    sleep(Duration::from_secs(4)).await;
+
+    for (key, expected) in [
+        (Key::N2, &laptop),
+        (Key::N1, &build_box),
+        // unknown nodes are ignored
+        (Key::N3, &build_box),
+        (Key::N2, &laptop),
+    ] {
+        commander.chord(&[Key::RightCtrl, key]).await;
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(Some(expected.id.clone()), commander.active_node(), "After {:?}", key);
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn nodes_keep_their_order_and_active_node_survives_expiry() {
+    let network = SimNetwork::default();
+    let commander = SimNode::spawn_with(Config {
+        nodes: vec![
+            NodeEntry { name: "gamma".to_string(), ..NodeEntry::default() },
+            NodeEntry { name: "alpha".to_string(), alias: "a".to_string(), ..NodeEntry::default() },
+        ],
+        bindings: BTreeMap::from([
+            ("RightCtrl+1".to_string(), Binding { switch_to: Some("a".to_string()), ..Binding::default() }),
+        ]),
+        ..config("commander", true)
+    }, &network, Impairment::default(), 1);
+    let alpha = SimNode::spawn("alpha", false, &network, Impairment::default(), 2);
+    let beta = SimNode::spawn("beta", false, &network, Impairment::default(), 3);
+    let gamma = SimNode::spawn("gamma", false, &network, Impairment::default(), 4);
+    sleep(Duration::from_secs(4)).await;
+
+    // configured nodes first, then the rest by name
+    for expected in [&gamma, &alpha, &beta, &commander, &gamma] {
+        commander.chord(&[Key::RightCtrl, Key::RightAlt]).await;
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(Some(expected.id.clone()), commander.active_node());
+    }
+
+    // aliases work like names
+    commander.chord(&[Key::RightCtrl, Key::N1]).await;
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(Some(alpha.id.clone()), commander.active_node());
+
+    // a node before the active one going away doesn't change the active node
+    commander.chord(&[Key::RightCtrl, Key::RightAlt]).await;
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(Some(beta.id.clone()), commander.active_node());
+    alpha.stop();
+    gamma.stop();
+    sleep(Duration::from_secs(15)).await;
+    assert_eq!(2, commander.app.lock().unwrap().nodes.len());
+    assert_eq!(Some(beta.id.clone()), commander.active_node());
+}
+
+/// Wait for a command to leave its mark.  Commands are real processes so this is real time.
+async fn wait_for_file(path: &Path) -> bool {
+    tokio::time::resume();
+    let mut found = false;
+    for _ in 0..100 {
+        if path.exists() {
+            found = true;
+            break;
+        }
+        sleep(Duration::from_millis(20)).await;
+    }
+    tokio::time::pause();
+    found
+}
+
+#[tokio::test(start_paused = true)]
+async fn bindings_run_commands_here_and_on_the_active_node() {
+    let dir = std::env::temp_dir().join(format!("rkvm2-sim-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let local = dir.join("local");
+    let remote_file = dir.join("remote");
+    let sneaky = dir.join("sneaky");
+
+    let network = SimNetwork::default();
+    let commander = SimNode::spawn_with(Config {
+        bindings: BTreeMap::from([
+            ("RightCtrl+L".to_string(), Binding {
+                run: Some(format!("touch {}", local.display())),
+                ..Binding::default()
+            }),
+            ("RightCtrl+R".to_string(), Binding {
+                run_on_active: Some("touch".to_string()),
+                ..Binding::default()
+            }),
+        ]),
+        ..config("commander", true)
+    }, &network, Impairment::default(), 1);
+    let remote = SimNode::spawn_with(Config {
+        commands: BTreeMap::from([
+            ("touch".to_string(), format!("touch {}", remote_file.display())),
+        ]),
+        ..config("remote", false)
+    }, &network, Impairment::default(), 2);
+    sleep(Duration::from_secs(4)).await;
+
+    commander.chord(&[Key::RightCtrl, Key::L]).await;
+    assert!(wait_for_file(&local).await);
+
+    commander.chord(&[Key::RightCtrl, Key::RightAlt]).await;
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(Some(remote.id.clone()), commander.active_node());
+    commander.chord(&[Key::RightCtrl, Key::R]).await;
+    assert!(wait_for_file(&remote_file).await);
+
+    // only the commander gets to ask, and only for allowed commands
+    remote.app.lock().unwrap().commands.insert("sneaky".to_string(), format!("touch {}", sneaky.display()));
+    remote.sender.send(Message {
+        header: Some(Header {
+            from_id: "someone".to_string(),
+            ..Header::default()
+        }),
+        payload: Some(Payload::RunCommandEvent(RunCommandEvent { name: "sneaky".to_string() })),
+    }).unwrap();
+    sleep(Duration::from_millis(100)).await;
+    assert!(!wait_for_file(&sneaky).await);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn binding_keys_are_not_forwarded() {
+    let network = SimNetwork::default();
+    let commander = SimNode::spawn("commander", true, &network, Impairment::default(), 1);
+    let mut remote = SimNode::spawn("remote", false, &network, Impairment::default(), 2);
+    sleep(Duration::from_secs(4)).await;
+
+    commander.chord(&[Key::RightCtrl, Key::RightAlt]).await;
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(Some(remote.id.clone()), commander.active_node());
+    assert_eq!(Vec::<KeyEvent>::new(), remote.take_key_log());
+
+    // a key that could start a binding still gets through in order when it doesn't
+    commander.chord(&[Key::RightCtrl, Key::C]).await;
+    commander.chord(&[Key::RightCtrl]).await;
+    commander.type_text(&[Key::A]).await;
+    sleep(Duration::from_millis(100)).await;
+    let key_event = |key: Key, down| KeyEvent { key: key.to_i32().unwrap(), down };
+    assert_eq!(vec![
+        key_event(Key::RightCtrl, true),
+        key_event(Key::C, true),
+        key_event(Key::C, false),
+        key_event(Key::RightCtrl, false),
+        key_event(Key::RightCtrl, true),
+        key_event(Key::RightCtrl, false),
+        key_event(Key::A, true),
+        key_event(Key::A, false),
+    ], remote.take_key_log());
+
+    // switching back isn't seen either
+    commander.chord(&[Key::RightCtrl, Key::Home]).await;
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(Some(commander.id.clone()), commander.active_node());
+    assert_eq!(Vec::<KeyEvent>::new(), remote.take_key_log());
+}
+
+#[tokio::test(start_paused = true)]
+async fn leader_and_double_tap_sequences() {
+    let network = SimNetwork::default();
+    let commander = SimNode::spawn_with(Config {
+        bindings: BTreeMap::from([
+            ("ScrollLock 2".to_string(), Binding {
+                switch_to: Some("two".to_string()),
+                ..Binding::default()
+            }),
+            ("RightCtrl RightCtrl".to_string(), Binding {
+                switch_to: Some("commander".to_string()),
+                ..Binding::default()
+            }),
+        ]),
+        ..config("commander", true)
+    }, &network, Impairment::default(), 1);
+    let mut two = SimNode::spawn("two", false, &network, Impairment::default(), 2);
+    sleep(Duration::from_secs(4)).await;
+
+    commander.type_text(&[Key::ScrollLock, Key::N2]).await;
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(Some(two.id.clone()), commander.active_node());
+    assert_eq!(Vec::<KeyEvent>::new(), two.take_key_log());
+
+    // too slow for a double tap, so both taps go through
+    commander.type_text(&[Key::RightCtrl]).await;
+    sleep(Duration::from_millis(500)).await;
+    commander.type_text(&[Key::RightCtrl]).await;
+    // and the sequence gave up before this
+    commander.type_text(&[Key::N2]).await;
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(Some(two.id.clone()), commander.active_node());
+    let key_event = |key: Key, down| KeyEvent { key: key.to_i32().unwrap(), down };
+    assert_eq!(vec![
+        key_event(Key::RightCtrl, true),
+        key_event(Key::RightCtrl, false),
+        key_event(Key::RightCtrl, true),
+        key_event(Key::RightCtrl, false),
+        key_event(Key::N2, true),
+        key_event(Key::N2, false),
+    ], two.take_key_log());
+
+    // the first tap of a double tap doesn't get in the way of a chord
+    commander.chord(&[Key::RightCtrl, Key::RightAlt]).await;
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(Some(commander.id.clone()), commander.active_node());
+    commander.chord(&[Key::RightCtrl, Key::RightAlt]).await;
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(Some(two.id.clone()), commander.active_node());
+
+    commander.type_text(&[Key::RightCtrl, Key::RightCtrl]).await;
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(Some(commander.id.clone()), commander.active_node());
+    assert_eq!(Vec::<KeyEvent>::new(), two.take_key_log());
+}
+
+#[tokio::test(start_paused = true)]
+async fn mouse_buttons_switch_nodes() {
+    let network = SimNetwork::default();
+    let commander = SimNode::spawn_with(Config {
+        bindings: BTreeMap::from([
+            ("ButtonSide".to_string(), Binding {
+                switch_to: Some("two".to_string()),
+                ..Binding::default()
+            }),
+            ("RightCtrl+ButtonExtra".to_string(), Binding {
+                switch_to: Some("commander".to_string()),
+                ..Binding::default()
+            }),
+        ]),
+        ..config("commander", true)
+    }, &network, Impairment::default(), 1);
+    let mut two = SimNode::spawn("two", false, &network, Impairment::default(), 2);
+    sleep(Duration::from_secs(4)).await;
+
+    commander.click(Button::Side).await;
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(Some(two.id.clone()), commander.active_node());
+
+    // other buttons go through and don't get in the way of bindings
+    commander.button(Button::Left, true);
+    commander.chord(&[Key::RightCtrl, Key::RightAlt]).await;
+    commander.button(Button::Left, false);
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(Some(commander.id.clone()), commander.active_node());
+    let key_event = |key: i32, down| KeyEvent { key, down };
+    assert_eq!(vec![
+        key_event(Button::Left as i32, true),
+        // released when we switched away
+        key_event(Button::Left as i32, false),
+    ], two.take_key_log());
+
+    commander.click(Button::Side).await;
+    commander.key(Key::RightCtrl, true);
+    commander.click(Button::Extra).await;
+    commander.key(Key::RightCtrl, false);
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(Some(commander.id.clone()), commander.active_node());
+    assert_eq!(Vec::<KeyEvent>::new(), two.take_key_log());
+}
+
+#[tokio::test(start_paused = true)]
+async fn keyboard_pointer_and_scroll_go_to_different_nodes() {
+    let network = SimNetwork::default();
+    let commander = SimNode::spawn_with(Config {
+        bindings: BTreeMap::from([
+            ("RightCtrl+K".to_string(), Binding {
+                switch_keyboard_to: Some("two".to_string()),
+                ..Binding::default()
+            }),
+            ("RightCtrl+P".to_string(), Binding {
+                switch_pointer_to: Some("three".to_string()),
+                ..Binding::default()
+            }),
+            ("RightCtrl+S".to_string(), Binding {
+                switch_scroll_to: Some("two".to_string()),
+                ..Binding::default()
+            }),
+        ]),
+        ..config("commander", true)
+    }, &network, Impairment::default(), 1);
+    let mut two = SimNode::spawn("two", false, &network, Impairment::default(), 2);
+    let mut three = SimNode::spawn("three", false, &network, Impairment::default(), 3);
+    sleep(Duration::from_secs(4)).await;
+
+    commander.chord(&[Key::RightCtrl, Key::K]).await;
+    commander.chord(&[Key::RightCtrl, Key::P]).await;
+    sleep(Duration::from_millis(100)).await;
+    two.take_key_log();
+    three.take_key_log();
+    three.take_motion_log();
+
+    commander.type_text(&[Key::A]).await;
+    commander.click(Button::Left).await;
+    commander.input_event(InputEventType::Wheel(MouseMoveEvent { delta: 1 }));
+    sleep(Duration::from_millis(100)).await;
+    let key_event = |key: i32, down| KeyEvent { key, down };
+    assert_eq!(vec![key_event(Key::A as i32, true), key_event(Key::A as i32, false)], two.take_key_log());
+    assert_eq!(vec![
+        key_event(Button::Left as i32, true),
+        key_event(Button::Left as i32, false),
+    ], three.take_key_log());
+    // the scroll wheel stays put until it's switched too
+    assert_eq!(Vec::<InputEventType>::new(), three.take_motion_log());
+    assert_eq!(Vec::<InputEventType>::new(), two.take_motion_log());
+
+    commander.chord(&[Key::RightCtrl, Key::S]).await;
+    commander.input_event(InputEventType::Wheel(MouseMoveEvent { delta: -1 }));
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(vec![InputEventType::Wheel(MouseMoveEvent { delta: -1 })], two.take_motion_log());
+
+    // everyone agrees where each kind of input goes
+    sleep(Duration::from_secs(2)).await;
+    for node in [&commander, &two, &three] {
+        let app = node.app.lock().unwrap();
+        assert_eq!(Some(&two.id), app.active_node.as_ref());
+        assert_eq!(Some(&three.id), app.pointer_node.as_ref());
+        assert_eq!(None, app.scroll_node.as_ref());
+    }
+
+    // switching everything brings them back together
+    commander.chord(&[Key::RightCtrl, Key::Home]).await;
+    sleep(Duration::from_millis(100)).await;
+    for node in [&commander, &two, &three] {
+        let app = node.app.lock().unwrap();
+        assert_eq!(Some(&commander.id), app.active_node.as_ref());
+        assert_eq!(None, app.pointer_node.as_ref());
+        assert_eq!(None, app.scroll_node.as_ref());
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn mirror_types_into_several_nodes() {
+    let network = SimNetwork::default();
+    let mut commander = SimNode::spawn_with(Config {
+        bindings: BTreeMap::from([
+            ("RightCtrl+M".to_string(), Binding {
+                mirror: vec!["two".to_string(), "three".to_string()],
+                ..Binding::default()
+            }),
+        ]),
+        ..config("commander", true)
+    }, &network, Impairment::default(), 1);
+    let mut two = SimNode::spawn("two", false, &network, Impairment::default(), 2);
+    let mut three = SimNode::spawn("three", false, &network, Impairment::default(), 3);
+    sleep(Duration::from_secs(4)).await;
+    for node in [&commander, &two, &three] {
+        node.notifier.take();
+    }
+
+    commander.chord(&[Key::RightCtrl, Key::M]).await;
+    sleep(Duration::from_secs(2)).await;
+    for node in [&commander, &two, &three] {
+        assert_eq!(vec![two.id.clone(), three.id.clone()], node.app.lock().unwrap().mirror);
+        assert_eq!(vec!["Mirroring to two, three".to_string()], node.notifier.take());
+    }
+    commander.take_key_log();
+
+    let key_event = |key: Key, down| KeyEvent { key: key as i32, down };
+    commander.type_text(&[Key::A]).await;
+    commander.key(Key::B, true);
+    sleep(Duration::from_millis(200)).await;
+    for node in [&mut commander, &mut two, &mut three] {
+        let key_log = node.take_key_log();
+        assert!(key_log.contains(&key_event(Key::A, false)), "{} got {:?}", node.id, key_log);
+    }
+
+    // the binding needs exactly its keys, so it won't fire with B held
+    commander.key(Key::B, false);
+    sleep(Duration::from_millis(200)).await;
+
+    // RightCtrl held long enough goes through to all of them.  Stopping with the binding lets go of it
+    // everywhere else.
+    commander.key(Key::RightCtrl, true);
+    sleep(Duration::from_millis(500)).await;
+    for node in [&mut two, &mut three] {
+        assert!(node.pressed().contains(&(Key::RightCtrl as i32)), "{} has {:?}", node.id, node.pressed);
+    }
+    commander.key(Key::M, true);
+    sleep(Duration::from_secs(2)).await;
+    for node in [&mut two, &mut three] {
+        assert!(node.pressed().is_empty(), "{} still has {:?}", node.id, node.pressed);
+        assert!(node.app.lock().unwrap().mirror.is_empty());
+    }
+    assert!(commander.app.lock().unwrap().mirror.is_empty());
+    commander.key(Key::M, false);
+    commander.key(Key::RightCtrl, false);
+    sleep(Duration::from_millis(200)).await;
+
+    // the binding starts it again and stops it
+    commander.chord(&[Key::RightCtrl, Key::M]).await;
+    sleep(Duration::from_millis(200)).await;
+    assert!(!commander.app.lock().unwrap().mirror.is_empty());
+    commander.chord(&[Key::RightCtrl, Key::M]).await;
+    sleep(Duration::from_millis(200)).await;
+    assert!(commander.app.lock().unwrap().mirror.is_empty());
+
+    two.take_key_log();
+    three.take_key_log();
+    commander.type_text(&[Key::C]).await;
+    sleep(Duration::from_millis(200)).await;
+    assert_eq!(Vec::<KeyEvent>::new(), two.take_key_log());
+    assert_eq!(Vec::<KeyEvent>::new(), three.take_key_log());
+}
+
+#[tokio::test(start_paused = true)]
+async fn backup_commander_takes_over_and_hands_back() {
+    let network = SimNetwork::default();
+    let primary_config = || Config {
+        commander_priority: 10,
+        ..config("primary", true)
+    };
+    let primary = SimNode::spawn_with(primary_config(), &network, Impairment::default(), 1);
+    let backup = SimNode::spawn_with(Config {
+        commander_priority: 5,
+        ..config("backup", true)
+    }, &network, Impairment::default(), 2);
+    let remote = SimNode::spawn("remote", false, &network, Impairment::default(), 3);
+    sleep(Duration::from_secs(4)).await;
+
+    // the backup defers to the primary
+    for node in [&primary, &backup, &remote] {
+        assert_eq!(Some(primary.id.clone()), node.app.lock().unwrap().commander_id);
+    }
+    primary.chord(&[Key::RightCtrl, Key::RightAlt]).await;
+    sleep(Duration::from_secs(4)).await;
+    let active_node = primary.active_node();
+    assert_ne!(Some(primary.id.clone()), active_node);
+    for node in [&backup, &remote] {
+        assert_eq!(active_node, node.active_node());
+    }
+
+    // the backup takes over when the primary goes away
+    primary.stop();
+    sleep(Duration::from_secs(15)).await;
+    for node in [&backup, &remote] {
+        assert_eq!(Some(backup.id.clone()), node.app.lock().unwrap().commander_id);
+    }
+    backup.chord(&[Key::RightCtrl, Key::Home]).await;
+    sleep(Duration::from_secs(4)).await;
+    for node in [&backup, &remote] {
+        assert_eq!(Some(backup.id.clone()), node.active_node());
+    }
+    backup.chord(&[Key::RightCtrl, Key::RightAlt]).await;
+    sleep(Duration::from_secs(4)).await;
+    for node in [&backup, &remote] {
+        assert_eq!(Some(remote.id.clone()), node.active_node());
+    }
+
+    // and hands back when it returns
+    let primary = SimNode::spawn_with(primary_config(), &network, Impairment::default(), 1);
+    sleep(Duration::from_secs(7)).await;
+    for node in [&primary, &backup, &remote] {
+        assert_eq!(Some(primary.id.clone()), node.app.lock().unwrap().commander_id);
+        assert_eq!(Some(primary.id.clone()), node.active_node());
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn any_node_takes_command_when_used() {
+    let network = SimNetwork::default();
+    let dynamic = |name| Config {
+        dynamic_commander: true,
+        ..config(name, false)
+    };
+    let a = SimNode::spawn_with(dynamic("a"), &network, Impairment::default(), 1);
+    let mut b = SimNode::spawn_with(dynamic("b"), &network, Impairment::default(), 2);
+    let c = SimNode::spawn_with(dynamic("c"), &network, Impairment::default(), 3);
+    sleep(Duration::from_secs(4)).await;
+    for node in [&a, &b, &c] {
+        assert_eq!(None, node.app.lock().unwrap().commander_id);
+    }
+
+    // using a's keyboard makes it the commander
+    a.type_text(&[Key::A]).await;
+    a.chord(&[Key::RightCtrl, Key::RightAlt]).await;
+    sleep(Duration::from_millis(100)).await;
+    for node in [&a, &b, &c] {
+        assert_eq!(Some(a.id.clone()), node.app.lock().unwrap().commander_id);
+        assert_eq!(Some(b.id.clone()), node.active_node());
+    }
+    b.take_key_log();
+
+    // then c's, which carries on sending to the active node
+    c.type_text(&[Key::C]).await;
+    sleep(Duration::from_millis(100)).await;
+    for node in [&a, &b, &c] {
+        assert_eq!(Some(c.id.clone()), node.app.lock().unwrap().commander_id);
+        assert_eq!(Some(b.id.clone()), node.active_node());
+    }
+    let key_event = |key: Key, down| KeyEvent { key: key.to_i32().unwrap(), down };
+    assert_eq!(vec![key_event(Key::C, true), key_event(Key::C, false)], b.take_key_log());
+
+    c.chord(&[Key::RightCtrl, Key::RightAlt]).await;
+    sleep(Duration::from_secs(4)).await;
+    for node in [&a, &b, &c] {
+        assert_eq!(Some(c.id.clone()), node.active_node());
+    }
+}
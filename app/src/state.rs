@@ -0,0 +1,16 @@
+use serde::{Deserialize, Serialize};
+
+/// What a node remembers across restarts
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SavedState {
+    /// The id of the node that had the keyboard
+    pub active_node: Option<String>,
+}
+
+/// Somewhere to keep the [SavedState]
+pub trait StateStore: Send {
+    /// The state saved last time, if there is any
+    fn load(&mut self) -> Option<SavedState>;
+    fn save(&mut self, state: &SavedState);
+}
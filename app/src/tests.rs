@@ -0,0 +1,1146 @@
+//! Drives a single [App] by hand with a clock that only moves when told to
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use num_traits::ToPrimitive;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use rkvm2_config::{Binding, Config, NodeEntry, NotificationConfig, NotificationEvent, NotificationStyle, Urgency};
+use rkvm2_proto::{ActiveNodeChangedEvent, ClaimEvent, ClipboardEvent, ControlCommand, ControlRequest, Header, InputEvent, InputKind, InputTarget, Key, KeyEvent, Message, MirrorEvent, MouseMoveEvent, NodeInfo, NotifyEvent, PingEvent, PongEvent, RequestHeader, ResponseCode, ResponseHeader, RunCommandEvent, Screen, StatusEvent};
+use rkvm2_proto::header::HeaderType;
+use rkvm2_proto::input_event::InputEventType;
+use rkvm2_proto::message::Payload;
+
+use crate::{App, Platform};
+use crate::clock::Clock;
+use crate::desktop::Notification;
+use crate::state::SavedState;
+use crate::sim::{SimClipboard, SimNotifier, SimStateStore};
+
+/// A message to send once the clock reaches the deadline
+type Alarm = (Instant, UnboundedSender<Message>, Message);
+
+/// A clock that stands still until advanced
+#[derive(Debug, Clone)]
+struct ManualClock {
+    now: Arc<Mutex<(Instant, SystemTime)>>,
+    alarms: Arc<Mutex<Vec<Alarm>>>,
+}
+impl ManualClock {
+    fn new() -> Self {
+        Self {
+            now: Arc::new(Mutex::new((Instant::now(), SystemTime::now()))),
+            alarms: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Move the clock on and send the messages that are due, in order
+    fn advance(&self, duration: Duration) {
+        let now = {
+            let mut now = self.now.lock().unwrap();
+            now.0 += duration;
+            now.1 += duration;
+            now.0
+        };
+        let mut alarms = self.alarms.lock().unwrap();
+        alarms.sort_by_key(|(deadline, _, _)| *deadline);
+        let due = alarms.iter().take_while(|(deadline, _, _)| *deadline <= now).count();
+        for (_, sender, message) in alarms.drain(..due) {
+            let _ = sender.send(message);
+        }
+    }
+}
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.now.lock().unwrap().0
+    }
+
+    fn system_time(&self) -> SystemTime {
+        self.now.lock().unwrap().1
+    }
+
+    fn send_after(&self, delay: Duration, sender: UnboundedSender<Message>, message: Message) {
+        let deadline = self.now() + delay;
+        self.alarms.lock().unwrap().push((deadline, sender, message));
+    }
+}
+
+fn config(name: &str, commander: bool) -> Config {
+    Config {
+        name: name.to_string(),
+        switch_keys: vec![Key::RightCtrl, Key::RightAlt],
+        commander_keys: vec![Key::RightCtrl, Key::Home],
+        sequence_timeout: 1000,
+        double_tap_timeout: 300,
+        commander,
+        ..Config::default()
+    }
+}
+
+/// An app and everything it sends
+struct Harness {
+    app: App,
+    clock: ManualClock,
+    clipboard: SimClipboard,
+    notifier: SimNotifier,
+    state: SimStateStore,
+    /// Messages the app wrote to its input device
+    input: UnboundedReceiver<Message>,
+    /// Messages the app sent to other nodes
+    net: UnboundedReceiver<Message>,
+    /// Messages the app sent itself
+    loopback: UnboundedReceiver<Message>,
+    /// Responses to control requests
+    control: UnboundedReceiver<Message>,
+}
+impl Harness {
+    /// The node's id is its name
+    fn new(config: Config) -> Self {
+        Self::with_state(config, SimStateStore::default())
+    }
+
+    /// Start with what an earlier app saved
+    fn with_state(config: Config, state: SimStateStore) -> Self {
+        let (input_sender, input) = unbounded_channel();
+        let (net_sender, net) = unbounded_channel();
+        let (message_sender, loopback) = unbounded_channel();
+        let (control_sender, control) = unbounded_channel();
+        let clock = ManualClock::new();
+        let clipboard = SimClipboard::default();
+        let notifier = SimNotifier::default();
+        let platform = Platform {
+            clock: Box::new(clock.clone()),
+            clipboard: Box::new(clipboard.clone()),
+            notifier: Box::new(notifier.clone()),
+            state: Box::new(state.clone()),
+            info: NodeInfo {
+                os: "test-os".to_string(),
+                version: "1.2.3".to_string(),
+                ..NodeInfo::default()
+            },
+        };
+        let app = App::new(config.name.clone(), config, platform, input_sender, net_sender, message_sender, control_sender);
+        Self {
+            app,
+            clock,
+            clipboard,
+            notifier,
+            state,
+            input,
+            net,
+            loopback,
+            control,
+        }
+    }
+
+    /// Handle the message, then everything the app sends itself because of it
+    fn handle(&mut self, message: Message) {
+        self.app.handle_message(message);
+        while let Ok(message) = self.loopback.try_recv() {
+            self.app.handle_message(message);
+        }
+    }
+
+    fn receive(&mut self, from_id: &str, payload: Payload) {
+        self.handle(Message {
+            header: Some(Header {
+                from_id: from_id.to_string(),
+                time: Some(self.clock.system_time().into()),
+                ..Header::default()
+            }),
+            payload: Some(payload),
+        });
+    }
+
+    fn ping_from(&mut self, from_id: &str, ping: PingEvent) {
+        self.receive(from_id, Payload::PingEvent(PingEvent {
+            name: from_id.to_string(),
+            ..ping
+        }));
+    }
+
+    /// Answer our next ping as a node whose clock is behind ours
+    fn pong_from(&mut self, from_id: &str, behind: Duration) {
+        self.tick();
+        let ping = self.sent().into_iter()
+            .find_map(|(_, payload)| match payload {
+                Payload::PingEvent(ping) => Some(ping),
+                _ => None,
+            })
+            .unwrap();
+        let their_time = self.clock.system_time() - behind;
+        self.receive(from_id, Payload::PongEvent(PongEvent {
+            sequence: ping.sequence,
+            ping_time: ping.time,
+            receive_time: Some(their_time.into()),
+            time: Some(their_time.into()),
+        }));
+    }
+
+    /// Input sent from another node at a time on its clock
+    fn receive_sent_at(&mut self, from_id: &str, time: SystemTime, payload: Payload) {
+        self.handle(Message {
+            header: Some(Header {
+                from_id: from_id.to_string(),
+                time: Some(time.into()),
+                ..Header::default()
+            }),
+            payload: Some(payload),
+        });
+    }
+
+    /// Let time go by, handling whatever the app asked to be woken for
+    fn wait(&mut self, duration: Duration) {
+        self.clock.advance(duration);
+        while let Ok(message) = self.loopback.try_recv() {
+            self.app.handle_message(message);
+        }
+    }
+
+    /// What the ping timer does
+    fn tick(&mut self) {
+        self.handle(Message {
+            header: None,
+            payload: Some(Payload::PingEvent(PingEvent::default())),
+        });
+    }
+
+    /// Input from this node's own keyboard
+    fn key(&mut self, key: Key, down: bool) {
+        self.handle(Message {
+            header: None,
+            payload: Some(Payload::InputEvent(InputEvent {
+                input_event_type: Some(InputEventType::Key(KeyEvent {
+                    key: key.to_i32().unwrap(),
+                    down,
+                })),
+            })),
+        });
+    }
+
+    /// Sideways motion from this node's own mouse
+    fn motion(&mut self, dx: i32) {
+        self.handle(Message {
+            header: None,
+            payload: Some(Payload::InputEvent(InputEvent {
+                input_event_type: Some(InputEventType::X(MouseMoveEvent { delta: dx })),
+            })),
+        });
+    }
+
+    fn chord(&mut self, keys: &[Key]) {
+        for key in keys {
+            self.key(*key, true);
+        }
+        for key in keys.iter().rev() {
+            self.key(*key, false);
+        }
+    }
+
+    fn switch(&mut self, id: &str, input: InputTarget) {
+        self.handle(Message {
+            header: None,
+            payload: Some(Payload::ActiveNodeChangedEvent(ActiveNodeChangedEvent {
+                id: id.to_string(),
+                target: input as i32,
+            })),
+        });
+    }
+
+    /// What rkvm2ctl does
+    fn control(&mut self, id: &str, command: ControlCommand, node: &str) {
+        self.control_with(id, ControlRequest {
+            command: command as i32,
+            node: node.to_string(),
+            ..ControlRequest::default()
+        });
+    }
+
+    fn control_with(&mut self, id: &str, request: ControlRequest) {
+        self.handle(Message {
+            header: Some(Header {
+                id: id.to_string(),
+                header_type: Some(HeaderType::Request(RequestHeader::default())),
+                ..Header::default()
+            }),
+            payload: Some(Payload::ControlRequest(request)),
+        });
+    }
+
+    /// The responses to control requests since the last call
+    fn responses(&mut self) -> Vec<(ResponseHeader, StatusEvent)> {
+        let mut responses = Vec::new();
+        while let Ok(message) = self.control.try_recv() {
+            if let (Some(Header { header_type: Some(HeaderType::Response(response)), .. }), Some(Payload::StatusEvent(status))) = (message.header, message.payload) {
+                responses.push((response, status));
+            }
+        }
+        responses
+    }
+
+    fn active_node(&self) -> Option<&str> {
+        self.app.active_node.as_deref()
+    }
+
+    fn node_ids(&self) -> Vec<&str> {
+        self.app.nodes.iter().map(|n| n.id.as_str()).collect()
+    }
+
+    fn commander_id(&self) -> Option<&str> {
+        self.app.commander_id.as_deref()
+    }
+
+    /// The payloads sent to other nodes since the last call, with who they were sent to
+    fn sent(&mut self) -> Vec<(String, Payload)> {
+        let mut sent = Vec::new();
+        while let Ok(message) = self.net.try_recv() {
+            if let (Some(header), Some(payload)) = (message.header, message.payload) {
+                sent.push((header.to_id, payload));
+            }
+        }
+        sent
+    }
+
+    /// The input events written to the input device since the last call
+    fn written(&mut self) -> Vec<InputEventType> {
+        let mut written = Vec::new();
+        while let Ok(message) = self.input.try_recv() {
+            if let Some(Payload::InputEvent(InputEvent { input_event_type: Some(input_event_type) })) = message.payload {
+                written.push(input_event_type);
+            }
+        }
+        written
+    }
+}
+
+fn key_event(key: Key, down: bool) -> InputEventType {
+    InputEventType::Key(KeyEvent {
+        key: key as i32,
+        down,
+    })
+}
+
+/// Just the key presses
+fn presses(input_event_types: Vec<InputEventType>) -> Vec<InputEventType> {
+    input_event_types.into_iter()
+        .filter(|input_event_type| matches!(input_event_type, InputEventType::Key(KeyEvent { down: true, .. })))
+        .collect()
+}
+
+/// The summaries and bodies
+fn titled(notifications: Vec<Notification>) -> Vec<(String, String)> {
+    notifications.into_iter().map(|n| (n.summary, n.body)).collect()
+}
+
+fn commander_ping(active_node: &str) -> PingEvent {
+    PingEvent {
+        commander: true,
+        active_node: active_node.to_string(),
+        ..PingEvent::default()
+    }
+}
+
+#[test]
+fn switch_keys_go_through_the_nodes_in_order() {
+    let mut harness = Harness::new(config("a", true));
+    harness.ping_from("c", PingEvent::default());
+    harness.ping_from("b", PingEvent::default());
+    assert_eq!(vec!["a", "b", "c"], harness.node_ids());
+    assert_eq!(Some("a"), harness.active_node());
+    harness.sent();
+
+    harness.chord(&[Key::RightCtrl, Key::RightAlt]);
+    assert_eq!(Some("b"), harness.active_node());
+    assert_eq!(vec!["b has the conn".to_string()], harness.notifier.take());
+    assert!(harness.sent().iter().any(|(_, payload)| payload == &Payload::ActiveNodeChangedEvent(ActiveNodeChangedEvent {
+        id: "b".to_string(),
+        target: InputTarget::All as i32,
+    })));
+
+    harness.chord(&[Key::RightCtrl, Key::RightAlt]);
+    assert_eq!(Some("c"), harness.active_node());
+    harness.chord(&[Key::RightCtrl, Key::RightAlt]);
+    assert_eq!(Some("a"), harness.active_node());
+    assert_eq!(vec!["c has the conn".to_string(), "I have the conn".to_string()], harness.notifier.take());
+
+    // the binding keys were never pressed anywhere
+    assert_eq!(Vec::<InputEventType>::new(), presses(harness.written()));
+    assert!(harness.sent().iter().all(|(_, payload)| !matches!(payload, Payload::InputEvent(_))));
+}
+
+#[test]
+fn input_goes_to_the_active_node() {
+    let mut harness = Harness::new(config("a", true));
+    harness.ping_from("b", PingEvent::default());
+
+    harness.chord(&[Key::A]);
+    assert_eq!(vec![key_event(Key::A, true), key_event(Key::A, false)], harness.written());
+
+    harness.switch("b", InputTarget::All);
+    harness.sent();
+    harness.chord(&[Key::A]);
+    assert_eq!(Vec::<InputEventType>::new(), harness.written());
+    let sent = harness.sent();
+    assert_eq!(2, sent.len());
+    for (to_id, payload) in sent {
+        assert_eq!("b", to_id);
+        assert!(matches!(payload, Payload::InputEvent(_)));
+    }
+}
+
+#[test]
+fn switching_away_releases_keys_and_shares_the_clipboard() {
+    let mut harness = Harness::new(config("a", true));
+    harness.ping_from("b", PingEvent::default());
+    *harness.clipboard.text.lock().unwrap() = "copied on a".to_string();
+
+    harness.key(Key::LeftShift, true);
+    harness.written();
+    harness.sent();
+    harness.switch("b", InputTarget::All);
+    assert_eq!(vec![key_event(Key::LeftShift, false)], harness.written());
+    assert!(harness.sent().iter().any(|(_, payload)| payload == &Payload::ClipboardEvent(ClipboardEvent {
+        data: b"copied on a".to_vec(),
+        mime_type: String::new(),
+    })));
+
+    harness.receive("b", Payload::ClipboardEvent(ClipboardEvent {
+        data: b"copied on b".to_vec(),
+        mime_type: String::new(),
+    }));
+    assert_eq!("copied on b", harness.clipboard.text.lock().unwrap().as_str());
+}
+
+#[test]
+fn quiet_nodes_expire() {
+    let mut harness = Harness::new(config("a", true));
+    harness.ping_from("b", PingEvent::default());
+    harness.clock.advance(Duration::from_secs(5));
+    harness.ping_from("c", PingEvent::default());
+    harness.tick();
+    assert_eq!(vec!["a", "b", "c"], harness.node_ids());
+
+    harness.clock.advance(Duration::from_secs(5));
+    harness.tick();
+    assert_eq!(vec!["a", "c"], harness.node_ids());
+
+    // hearing from it again brings it back
+    harness.ping_from("b", PingEvent::default());
+    assert_eq!(vec!["a", "b", "c"], harness.node_ids());
+}
+
+#[test]
+fn active_node_goes_back_to_the_commander_when_it_expires() {
+    let mut harness = Harness::new(config("a", true));
+    harness.ping_from("b", PingEvent::default());
+    harness.switch("b", InputTarget::All);
+    harness.switch("a", InputTarget::Pointer);
+    harness.notifier.take();
+
+    harness.clock.advance(Duration::from_secs(10));
+    harness.tick();
+    assert_eq!(vec!["a"], harness.node_ids());
+    assert_eq!(Some("a"), harness.active_node());
+    assert_eq!(None, harness.app.pointer_node);
+    assert_eq!(vec!["I have the conn".to_string()], harness.notifier.take());
+}
+
+#[test]
+fn nodes_follow_the_commanders_pings() {
+    let mut harness = Harness::new(config("r", false));
+    assert_eq!(None, harness.active_node());
+
+    harness.ping_from("cmd", commander_ping("r"));
+    assert_eq!(Some("cmd"), harness.commander_id());
+    assert_eq!(Some("r"), harness.active_node());
+    assert_eq!(vec!["I have the conn".to_string()], harness.notifier.take());
+
+    // only the commander says where input goes
+    harness.ping_from("x", PingEvent {
+        active_node: "cmd".to_string(),
+        ..PingEvent::default()
+    });
+    assert_eq!(Some("r"), harness.active_node());
+
+    harness.ping_from("cmd", PingEvent {
+        pointer_node: "r".to_string(),
+        ..commander_ping("cmd")
+    });
+    assert_eq!(Some("cmd"), harness.active_node());
+    assert_eq!(Some("r"), harness.app.pointer_node.as_deref());
+    assert_eq!(None, harness.app.scroll_node);
+
+    // nothing changed, nothing to say
+    harness.notifier.take();
+    harness.ping_from("cmd", PingEvent {
+        pointer_node: "r".to_string(),
+        ..commander_ping("cmd")
+    });
+    assert_eq!(Vec::<String>::new(), harness.notifier.take());
+}
+
+#[test]
+fn commanders_do_not_share_the_active_node() {
+    let mut harness = Harness::new(config("a", true));
+    harness.ping_from("b", PingEvent::default());
+    harness.tick();
+    let pings: Vec<PingEvent> = harness.sent().into_iter()
+        .filter_map(|(_, payload)| match payload {
+            Payload::PingEvent(ping) => Some(ping),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(1, pings.len());
+    assert!(pings[0].commander);
+    assert_eq!("a", pings[0].active_node);
+
+    // a node that isn't in charge keeps quiet about it
+    let mut harness = Harness::new(config("r", false));
+    harness.ping_from("cmd", commander_ping("r"));
+    harness.sent();
+    harness.tick();
+    assert!(harness.sent().iter().all(|(_, payload)| match payload {
+        Payload::PingEvent(ping) => ping.active_node.is_empty(),
+        _ => true,
+    }));
+}
+
+#[test]
+fn highest_priority_commander_is_in_charge_until_it_expires() {
+    let mut harness = Harness::new(config("r", false));
+    harness.ping_from("backup", PingEvent {
+        priority: 5,
+        ..commander_ping("")
+    });
+    harness.ping_from("primary", PingEvent {
+        priority: 10,
+        ..commander_ping("")
+    });
+    assert_eq!(Some("primary"), harness.commander_id());
+    // finding the first commander isn't news, but a change is
+    assert_eq!(vec!["primary has the command".to_string()], harness.notifier.take());
+
+    harness.clock.advance(Duration::from_secs(6));
+    harness.ping_from("backup", PingEvent {
+        priority: 5,
+        ..commander_ping("")
+    });
+    harness.clock.advance(Duration::from_secs(4));
+    harness.tick();
+    assert_eq!(Some("backup"), harness.commander_id());
+    assert_eq!(vec!["backup has the command".to_string()], harness.notifier.take());
+}
+
+#[test]
+fn backup_commanders_keep_their_own_input() {
+    let mut harness = Harness::new(config("backup", true));
+    harness.ping_from("primary", PingEvent {
+        priority: 1,
+        ..commander_ping("primary")
+    });
+    assert_eq!(Some("primary"), harness.commander_id());
+    assert_eq!(Some("primary"), harness.active_node());
+    harness.sent();
+
+    harness.chord(&[Key::A]);
+    harness.motion(5);
+    assert_eq!(vec![
+        key_event(Key::A, true),
+        key_event(Key::A, false),
+        InputEventType::X(MouseMoveEvent { delta: 5 }),
+    ], harness.written());
+    assert!(harness.sent().is_empty());
+}
+
+#[test]
+fn latest_claim_beats_priority() {
+    let mut harness = Harness::new(config("a", true));
+    harness.ping_from("b", PingEvent {
+        commander: true,
+        priority: 10,
+        ..PingEvent::default()
+    });
+    assert_eq!(Some("b"), harness.commander_id());
+
+    harness.receive("c", Payload::ClaimEvent(ClaimEvent { claim: 1 }));
+    // claims only count from nodes we know
+    assert_eq!(Some("b"), harness.commander_id());
+
+    harness.ping_from("c", PingEvent::default());
+    harness.receive("c", Payload::ClaimEvent(ClaimEvent { claim: 1 }));
+    assert_eq!(Some("c"), harness.commander_id());
+
+    // pings carry the claim so it isn't forgotten
+    harness.ping_from("c", PingEvent {
+        commander: true,
+        ..PingEvent::default()
+    });
+    assert_eq!(Some("c"), harness.commander_id());
+}
+
+#[test]
+fn late_pointer_motion_is_dropped() {
+    let mut harness = Harness::new(config("r", false));
+    harness.ping_from("cmd", commander_ping("r"));
+    harness.pong_from("cmd", Duration::ZERO);
+    let motion = |delta| Payload::InputEvent(InputEvent {
+        input_event_type: Some(InputEventType::X(MouseMoveEvent { delta })),
+    });
+
+    harness.receive("cmd", motion(1));
+    assert_eq!(vec![InputEventType::X(MouseMoveEvent { delta: 1 })], harness.written());
+
+    // sent a second ago
+    let late = Message {
+        header: Some(Header {
+            from_id: "cmd".to_string(),
+            time: Some(harness.clock.system_time().into()),
+            ..Header::default()
+        }),
+        payload: Some(motion(2)),
+    };
+    harness.clock.advance(Duration::from_secs(1));
+    harness.handle(late);
+    assert_eq!(Vec::<InputEventType>::new(), harness.written());
+}
+
+#[test]
+fn motion_is_not_stale_until_the_clock_offset_is_known() {
+    let mut harness = Harness::new(config("r", false));
+    harness.ping_from("cmd", commander_ping("r"));
+    let motion = |delta| Payload::InputEvent(InputEvent {
+        input_event_type: Some(InputEventType::X(MouseMoveEvent { delta })),
+    });
+
+    // the commander's clock is a second behind, which looks late until its pong
+    let behind = harness.clock.system_time() - Duration::from_secs(1);
+    harness.receive_sent_at("cmd", behind, motion(1));
+    assert_eq!(vec![InputEventType::X(MouseMoveEvent { delta: 1 })], harness.written());
+
+    harness.pong_from("cmd", Duration::from_secs(1));
+    harness.receive_sent_at("cmd", behind, motion(2));
+    assert_eq!(vec![InputEventType::X(MouseMoveEvent { delta: 2 })], harness.written());
+
+    // the same pong again, as if the clock had jumped, isn't a new sample
+    let their_time = harness.clock.system_time() - Duration::from_secs(5);
+    harness.receive("cmd", Payload::PongEvent(PongEvent {
+        sequence: 1,
+        ping_time: Some(harness.clock.system_time().into()),
+        receive_time: Some(their_time.into()),
+        time: Some(their_time.into()),
+    }));
+    harness.receive_sent_at("cmd", behind, motion(3));
+    assert_eq!(vec![InputEventType::X(MouseMoveEvent { delta: 3 })], harness.written());
+    harness.receive_sent_at("cmd", behind - Duration::from_secs(1), motion(4));
+    assert_eq!(Vec::<InputEventType>::new(), harness.written());
+}
+
+#[test]
+fn keys_that_might_start_a_binding_are_let_through_soon() {
+    let mut harness = Harness::new(Config {
+        screen_width: 1920,
+        screen_height: 1080,
+        right: "b".to_string(),
+        ..config("a", true)
+    });
+    harness.ping_from("b", PingEvent {
+        screen: Some(Screen {
+            width: 1920,
+            height: 1080,
+            ..Screen::default()
+        }),
+        ..PingEvent::default()
+    });
+
+    // RightAlt could start the switch keys, but alt+hover shouldn't wait for another key
+    harness.key(Key::RightAlt, true);
+    harness.motion(10);
+    assert_eq!(vec![InputEventType::X(MouseMoveEvent { delta: 10 })], harness.written());
+    harness.wait(crate::HOLD_TIMEOUT);
+    assert_eq!(vec![key_event(Key::RightAlt, true)], harness.written());
+    harness.key(Key::RightAlt, false);
+    assert_eq!(vec![key_event(Key::RightAlt, false)], harness.written());
+
+    // a held key goes to the node it was pressed on, even when the pointer leaves first
+    harness.key(Key::RightCtrl, true);
+    harness.sent();
+    harness.motion(2000);
+    assert_eq!(Some("b"), harness.active_node());
+    assert_eq!(vec![key_event(Key::RightCtrl, true), key_event(Key::RightCtrl, false)], harness.written());
+    harness.wait(crate::HOLD_TIMEOUT);
+    assert!(!harness.sent().iter().any(|(_, payload)| matches!(payload, Payload::InputEvent(InputEvent { input_event_type: Some(InputEventType::Key(_)) }))));
+}
+
+#[test]
+fn sequences_time_out() {
+    let mut harness = Harness::new(Config {
+        bindings: BTreeMap::from([
+            ("ScrollLock 2".to_string(), Binding {
+                switch_to: Some("b".to_string()),
+                ..Binding::default()
+            }),
+            ("RightCtrl RightCtrl".to_string(), Binding {
+                switch_to: Some("b".to_string()),
+                ..Binding::default()
+            }),
+        ]),
+        ..config("a", true)
+    });
+    harness.ping_from("b", PingEvent::default());
+
+    harness.chord(&[Key::ScrollLock]);
+    assert_eq!(Vec::<InputEventType>::new(), harness.written());
+    // the leader is let through once it's clear it wasn't a binding, without waiting for another key
+    harness.wait(Duration::from_secs(1));
+    assert_eq!(vec![key_event(Key::ScrollLock, true), key_event(Key::ScrollLock, false)], harness.written());
+    harness.chord(&[Key::N2]);
+    assert_eq!(Some("a"), harness.active_node());
+    assert_eq!(vec![key_event(Key::N2, true), key_event(Key::N2, false)], harness.written());
+
+    // same for a single tap of a double tap binding
+    harness.chord(&[Key::RightCtrl]);
+    assert_eq!(Vec::<InputEventType>::new(), harness.written());
+    harness.wait(Duration::from_millis(300));
+    assert_eq!(vec![key_event(Key::RightCtrl, true), key_event(Key::RightCtrl, false)], harness.written());
+    assert_eq!(Some("a"), harness.active_node());
+
+    harness.chord(&[Key::ScrollLock]);
+    harness.clock.advance(Duration::from_millis(500));
+    harness.chord(&[Key::N2]);
+    assert_eq!(Some("b"), harness.active_node());
+    assert_eq!(Vec::<InputEventType>::new(), presses(harness.written()));
+}
+
+#[test]
+fn active_node_comes_back_after_a_restart() {
+    let mut harness = Harness::new(config("a", true));
+    harness.ping_from("b", PingEvent::default());
+    harness.ping_from("c", PingEvent::default());
+    harness.switch("b", InputTarget::All);
+    let saved = harness.state.state.lock().unwrap().clone().unwrap();
+    assert_eq!(Some("b"), saved.active_node.as_deref());
+
+    let mut harness = Harness::with_state(config("a", true), harness.state);
+    assert_eq!(Some("a"), harness.active_node());
+    harness.ping_from("c", PingEvent::default());
+    assert_eq!(Some("a"), harness.active_node());
+    // still remembered if we restart again before it's back
+    assert_eq!(Some("b"), harness.state.state.lock().unwrap().as_ref().unwrap().active_node.as_deref());
+    harness.ping_from("b", PingEvent::default());
+    assert_eq!(Some("b"), harness.active_node());
+
+    // only the once
+    harness.switch("a", InputTarget::All);
+    harness.ping_from("b", PingEvent::default());
+    assert_eq!(Some("a"), harness.active_node());
+}
+
+#[test]
+fn switching_before_the_node_is_back_forgets_it() {
+    let state = SimStateStore::default();
+    *state.state.lock().unwrap() = Some(SavedState {
+        active_node: Some("b".to_string()),
+    });
+    let mut harness = Harness::with_state(config("a", true), state);
+    harness.ping_from("c", PingEvent::default());
+    harness.switch("c", InputTarget::All);
+    harness.ping_from("b", PingEvent::default());
+    assert_eq!(Some("c"), harness.active_node());
+}
+
+#[test]
+fn another_commander_being_in_charge_forgets_the_node_to_go_back_to() {
+    let state = SimStateStore::default();
+    *state.state.lock().unwrap() = Some(SavedState {
+        active_node: Some("b".to_string()),
+    });
+    let mut harness = Harness::with_state(config("backup", true), state);
+    harness.ping_from("primary", PingEvent {
+        priority: 1,
+        ..commander_ping("primary")
+    });
+    assert_eq!(Some("primary"), harness.state.state.lock().unwrap().as_ref().unwrap().active_node.as_deref());
+
+    // long after, the backup takes over and b turns up
+    harness.clock.advance(Duration::from_secs(60));
+    harness.tick();
+    assert_eq!(Some("backup"), harness.commander_id());
+    harness.ping_from("b", PingEvent::default());
+    assert_eq!(Some("backup"), harness.active_node());
+}
+
+#[test]
+fn idle_nodes_give_the_keyboard_back() {
+    let mut harness = Harness::new(Config {
+        nodes: vec![
+            NodeEntry {
+                name: "b".to_string(),
+                idle_timeout: Some(10),
+                idle_command: "lock".to_string(),
+                ..NodeEntry::default()
+            },
+            NodeEntry {
+                name: "c".to_string(),
+                ..NodeEntry::default()
+            },
+        ],
+        ..config("a", true)
+    });
+    // time passes with the other nodes still pinging
+    let wait = |harness: &mut Harness, minutes: u64| {
+        for _ in 0..minutes * 20 {
+            harness.clock.advance(Duration::from_secs(3));
+            harness.ping_from("b", PingEvent::default());
+            harness.ping_from("c", PingEvent::default());
+            harness.tick();
+        }
+    };
+    harness.ping_from("b", PingEvent::default());
+    harness.ping_from("c", PingEvent::default());
+    harness.switch("b", InputTarget::All);
+
+    wait(&mut harness, 6);
+    harness.chord(&[Key::A]);
+    wait(&mut harness, 6);
+    assert_eq!(Some("b"), harness.active_node());
+    harness.sent();
+
+    wait(&mut harness, 5);
+    assert_eq!(Some("a"), harness.active_node());
+    assert!(harness.sent().iter().any(|(to_id, payload)| to_id == "b" && payload == &Payload::RunCommandEvent(RunCommandEvent {
+        name: "lock".to_string(),
+    })));
+
+    // nodes without a timeout keep it
+    harness.switch("c", InputTarget::All);
+    wait(&mut harness, 30);
+    assert_eq!(Some("c"), harness.active_node());
+}
+
+#[test]
+fn pings_describe_the_node() {
+    let mut harness = Harness::new(config("a", true));
+    harness.clock.advance(Duration::from_secs(90));
+    harness.tick();
+    let info = harness.sent().into_iter()
+        .find_map(|(_, payload)| match payload {
+            Payload::PingEvent(ping) => ping.info,
+            _ => None,
+        })
+        .unwrap();
+    assert_eq!(NodeInfo {
+        os: "test-os".to_string(),
+        version: "1.2.3".to_string(),
+        uptime: 90,
+        ..NodeInfo::default()
+    }, info);
+
+    let info = NodeInfo {
+        os: "linux".to_string(),
+        version: "0.2.0".to_string(),
+        input_kinds: vec![InputKind::Keys as i32],
+        clipboard_formats: vec!["text/plain".to_string()],
+        uptime: 5,
+        input_kinds_announced: true,
+    };
+    harness.ping_from("b", PingEvent {
+        info: Some(info.clone()),
+        ..PingEvent::default()
+    });
+    let b = harness.app.nodes.iter().find(|n| n.id == "b").unwrap();
+    assert_eq!(info, b.info);
+    assert!(b.can_write(InputKind::Keys));
+    assert!(!b.can_write(InputKind::AbsolutePointer));
+
+    // saying it can't write anything isn't the same as not saying
+    harness.ping_from("c", PingEvent {
+        info: Some(NodeInfo {
+            input_kinds_announced: true,
+            ..NodeInfo::default()
+        }),
+        ..PingEvent::default()
+    });
+    harness.ping_from("d", PingEvent::default());
+    let can_write_keys = |id: &str| harness.app.nodes.iter().find(|n| n.id == id).unwrap().can_write(InputKind::Keys);
+    assert!(!can_write_keys("c"));
+    assert!(can_write_keys("d"));
+}
+
+#[test]
+fn direction_keys_with_nowhere_to_go_are_let_through() {
+    let mut harness = Harness::new(Config {
+        left_keys: vec![Key::LeftCtrl, Key::LeftAlt, Key::Left],
+        ..config("a", true)
+    });
+    harness.ping_from("b", PingEvent::default());
+    harness.written();
+
+    // no layout, so ctrl+alt+left is the desktop's
+    harness.chord(&[Key::LeftCtrl, Key::LeftAlt, Key::Left]);
+    assert_eq!(Some("a"), harness.active_node());
+    assert_eq!(vec![
+        key_event(Key::LeftCtrl, true),
+        key_event(Key::LeftAlt, true),
+        key_event(Key::Left, true),
+        key_event(Key::Left, false),
+        key_event(Key::LeftAlt, false),
+        key_event(Key::LeftCtrl, false),
+    ], harness.written());
+}
+
+#[test]
+fn pointer_positions_only_go_to_nodes_that_can_place_the_pointer() {
+    let mut harness = Harness::new(Config {
+        screen_width: 1920,
+        screen_height: 1080,
+        right: "b".to_string(),
+        left: "c".to_string(),
+        ..config("a", true)
+    });
+    let screen = Some(Screen {
+        width: 1920,
+        height: 1080,
+        ..Screen::default()
+    });
+    harness.ping_from("b", PingEvent {
+        screen: screen.clone(),
+        info: Some(NodeInfo {
+            input_kinds: vec![InputKind::Keys as i32, InputKind::RelativePointer as i32],
+            input_kinds_announced: true,
+            ..NodeInfo::default()
+        }),
+        ..PingEvent::default()
+    });
+    // says nothing, so it gets the benefit of the doubt
+    harness.ping_from("c", PingEvent {
+        screen,
+        ..PingEvent::default()
+    });
+    let positions = |harness: &mut Harness| -> Vec<String> {
+        harness.sent().into_iter()
+            .filter(|(_, payload)| matches!(payload, Payload::InputEvent(InputEvent { input_event_type: Some(InputEventType::Position(_)) })))
+            .map(|(to_id, _)| to_id)
+            .collect()
+    };
+    positions(&mut harness);
+
+    harness.motion(2000);
+    assert_eq!(Some("b"), harness.active_node());
+    assert_eq!(Vec::<String>::new(), positions(&mut harness));
+
+    harness.motion(-4000);
+    assert_eq!(Some("a"), harness.active_node());
+    harness.motion(-2000);
+    assert_eq!(Some("c"), harness.active_node());
+    assert_eq!(vec!["c".to_string()], positions(&mut harness));
+}
+
+#[test]
+fn control_requests_switch_and_report_status() {
+    let mut harness = Harness::new(config("a", true));
+    harness.ping_from("b", PingEvent::default());
+    harness.ping_from("c", PingEvent {
+        info: Some(NodeInfo {
+            os: "linux".to_string(),
+            ..NodeInfo::default()
+        }),
+        ..PingEvent::default()
+    });
+    harness.clock.advance(Duration::from_secs(2));
+
+    harness.control("1", ControlCommand::Status, "");
+    let responses = harness.responses();
+    assert_eq!(1, responses.len());
+    let (response, status) = &responses[0];
+    assert_eq!(("1", ResponseCode::Ok), (response.request_id.as_str(), response.code()));
+    assert_eq!(("a", "a", "a"), (status.id.as_str(), status.commander.as_str(), status.active_node.as_str()));
+    assert_eq!(vec![("a", true, 0), ("b", false, 2000), ("c", false, 2000)],
+               status.nodes.iter().map(|n| (n.id.as_str(), n.local, n.last_heard)).collect::<Vec<_>>());
+    assert_eq!("linux", status.nodes[2].info.as_ref().unwrap().os);
+
+    harness.control("2", ControlCommand::Switch, "c");
+    assert_eq!(Some("c"), harness.active_node());
+    let (response, status) = harness.responses().remove(0);
+    assert_eq!(ResponseCode::Ok, response.code());
+    assert_eq!("c", status.active_node);
+
+    harness.control("3", ControlCommand::Next, "");
+    assert_eq!(Some("a"), harness.active_node());
+
+    harness.control("4", ControlCommand::Switch, "nowhere");
+    assert_eq!(Some("a"), harness.active_node());
+    let (response, _) = harness.responses().pop().unwrap();
+    assert_eq!((ResponseCode::Error, "No node called nowhere"), (response.code(), response.message.as_str()));
+
+    *harness.clipboard.text.lock().unwrap() = "shared".to_string();
+    harness.sent();
+    harness.control("5", ControlCommand::SendClipboard, "");
+    assert!(harness.sent().iter().any(|(_, payload)| payload == &Payload::ClipboardEvent(ClipboardEvent {
+        data: b"shared".to_vec(),
+        mime_type: "".to_string(),
+    })));
+    assert_eq!(ResponseCode::Ok, harness.responses().pop().unwrap().0.code());
+}
+
+#[test]
+fn watchers_hear_about_changes_until_they_cancel() {
+    let mut harness = Harness::new(config("a", true));
+    harness.ping_from("b", PingEvent::default());
+
+    harness.control("w", ControlCommand::Watch, "");
+    let (response, status) = harness.responses().remove(0);
+    assert_eq!(("w", ResponseCode::Update), (response.request_id.as_str(), response.code()));
+    assert_eq!("a", status.active_node);
+
+    // pings that change nothing but the numbers aren't news
+    harness.clock.advance(Duration::from_secs(1));
+    harness.ping_from("b", PingEvent::default());
+    harness.tick();
+    assert!(harness.responses().is_empty());
+
+    harness.switch("b", InputTarget::Pointer);
+    let updates = harness.responses();
+    assert_eq!(vec![("a", "b")], updates.iter().map(|(_, s)| (s.active_node.as_str(), s.pointer_node.as_str())).collect::<Vec<_>>());
+    assert!(updates.iter().all(|(r, _)| r.request_id == "w" && r.code() == ResponseCode::Update));
+
+    harness.ping_from("c", PingEvent::default());
+    assert_eq!(vec![3], harness.responses().iter().map(|(_, s)| s.nodes.len()).collect::<Vec<_>>());
+
+    harness.handle(Message {
+        header: Some(Header {
+            id: "w".to_string(),
+            header_type: Some(HeaderType::Request(RequestHeader { cancel: true })),
+            ..Header::default()
+        }),
+        payload: Some(Payload::ControlRequest(ControlRequest::default())),
+    });
+    harness.switch("c", InputTarget::All);
+    assert!(harness.responses().is_empty());
+}
+
+#[test]
+fn notifications_find_their_way_to_the_commander() {
+    let notify = |text: &str| Message {
+        header: None,
+        payload: Some(Payload::NotifyEvent(NotifyEvent { text: text.to_string() })),
+    };
+    let mut commander = Harness::new(config("a", true));
+    commander.ping_from("b", PingEvent::default());
+    commander.notifier.take();
+    commander.receive("b", Payload::NotifyEvent(NotifyEvent { text: "Build done".to_string() }));
+    assert_eq!(vec![("b".to_string(), "Build done".to_string())], titled(commander.notifier.take_all()));
+
+    let mut harness = Harness::new(config("b", false));
+    harness.ping_from("a", commander_ping("a"));
+    harness.notifier.take();
+    harness.sent();
+    harness.handle(notify("Build done"));
+    assert_eq!(vec![("a".to_string(), Payload::NotifyEvent(NotifyEvent { text: "Build done".to_string() }))], harness.sent());
+    assert!(harness.notifier.take().is_empty());
+
+    // the desktop already showed it, but one from rkvm2ctl needs showing
+    harness.ping_from("a", commander_ping("b"));
+    assert_eq!(Some("b"), harness.active_node());
+    harness.notifier.take();
+    harness.sent();
+    harness.handle(notify("Build done"));
+    harness.control_with("1", ControlRequest {
+        command: ControlCommand::Notify as i32,
+        text: "Tests passed".to_string(),
+        ..ControlRequest::default()
+    });
+    assert_eq!(vec![("b".to_string(), "Tests passed".to_string())], titled(harness.notifier.take_all()));
+    assert!(!harness.sent().iter().any(|(_, payload)| matches!(payload, Payload::NotifyEvent(_))));
+    assert_eq!(ResponseCode::Ok, harness.responses().pop().unwrap().0.code());
+}
+
+#[test]
+fn notifications_are_shown_as_configured() {
+    let mut config = config("a", true);
+    config.notifications = NotificationConfig {
+        defaults: NotificationStyle {
+            summary: Some("KVM {node}".to_string()),
+            urgency: Some(Urgency::Low),
+            timeout: Some(2000),
+            icon: Some("input-keyboard".to_string()),
+            ..NotificationStyle::default()
+        },
+        events: BTreeMap::from([
+            (NotificationEvent::Switch, NotificationStyle {
+                body: Some("Now on {node}".to_string()),
+                urgency: Some(Urgency::Critical),
+                ..NotificationStyle::default()
+            }),
+            (NotificationEvent::Join, NotificationStyle {
+                enabled: Some(true),
+                ..NotificationStyle::default()
+            }),
+            (NotificationEvent::Mirror, NotificationStyle {
+                enabled: Some(false),
+                ..NotificationStyle::default()
+            }),
+        ]),
+    };
+    let mut harness = Harness::new(config);
+    harness.notifier.take();
+
+    harness.ping_from("b", PingEvent::default());
+    assert_eq!(vec![Notification {
+        event: NotificationEvent::Join,
+        summary: "KVM b".to_string(),
+        body: "b joined".to_string(),
+        urgency: Some(Urgency::Low),
+        timeout: Some(2000),
+        icon: "input-keyboard".to_string(),
+        replace: true,
+    }], harness.notifier.take_all());
+
+    harness.switch("b", InputTarget::All);
+    let switched = harness.notifier.take_all();
+    assert_eq!(vec![("KVM b".to_string(), "Now on b".to_string())], titled(switched.clone()));
+    assert_eq!(Some(Urgency::Critical), switched[0].urgency);
+
+    harness.handle(Message {
+        header: None,
+        payload: Some(Payload::MirrorEvent(MirrorEvent { ids: vec!["b".to_string()] })),
+    });
+    assert!(harness.notifier.take().is_empty());
+
+    // leaving is off unless asked for
+    harness.clock.advance(Duration::from_secs(60));
+    harness.tick();
+    assert_eq!(vec!["a"], harness.node_ids());
+    assert_eq!(vec![("KVM a".to_string(), "Now on a".to_string())], titled(harness.notifier.take_all()));
+}
+
+#[test]
+fn nodes_join_and_leave_by_their_alias() {
+    let enabled = NotificationStyle {
+        enabled: Some(true),
+        ..NotificationStyle::default()
+    };
+    let mut harness = Harness::new(Config {
+        nodes: vec![NodeEntry {
+            name: "b".to_string(),
+            alias: "laptop".to_string(),
+            ..NodeEntry::default()
+        }],
+        notifications: NotificationConfig {
+            events: BTreeMap::from([
+                (NotificationEvent::Join, enabled.clone()),
+                (NotificationEvent::Leave, enabled),
+            ]),
+            ..NotificationConfig::default()
+        },
+        ..config("a", true)
+    });
+    harness.notifier.take();
+
+    harness.ping_from("b", PingEvent::default());
+    harness.clock.advance(Duration::from_secs(60));
+    harness.tick();
+    let bodies: Vec<String> = harness.notifier.take_all().into_iter().map(|n| n.body).collect();
+    assert_eq!(vec!["laptop joined".to_string(), "laptop left".to_string()], bodies);
+}
@@ -1,14 +1,19 @@
-use std::{fs::File, io::BufReader};
+use std::{fs, fs::File, io::BufReader};
+use std::collections::BTreeMap;
 use std::path::Path;
 use std::process::exit;
+use std::str::FromStr;
 
 use clap_serde_derive::{
     clap::{self, Parser},
     ClapSerde,
 };
 use directories::ProjectDirs;
-use serde::Serialize;
-use rkvm2_proto::Key;
+use serde::{Deserialize, Serialize};
+use rkvm2_proto::{Button, Key};
+use uuid::Uuid;
+
+const NODE_ID_FILE: &str = "node_id";
 
 #[derive(Parser)]
 #[command(author, version=env!("VERSION_STRING"), about)]
@@ -31,10 +36,18 @@ struct Args {
 
 #[derive(ClapSerde, Debug, Serialize)]
 pub struct Config {
+    /// rkvm2 config: The name other nodes show for this node.  Default is the hostname
+    #[arg(short = 'n', long = "name")]
+    pub name: String,
+
     /// rkvm2 config: The broadcast address to use.  Default 192.168.24.255:45321
     #[arg(short = 'b', long = "broadcast-address")]
     pub broadcast_address: String,
 
+    /// rkvm2 config: The group this node belongs to.  Nodes only talk to nodes in the same group.  Default is no group
+    #[arg(short = 'G', long = "group")]
+    pub group: String,
+
     /// rkvm2 config: The keys to use to switch to the next node.  Default RightCtrl+RightAlt
     #[arg(short = 's', long = "switch-keys")]
     pub switch_keys: Vec<Key>,
@@ -43,15 +56,250 @@ pub struct Config {
     #[arg(short = 'S', long = "switch-commander-keys")]
     pub commander_keys: Vec<Key>,
 
+    /// rkvm2 config: The width of this node's screen in pixels.  Default 1920
+    #[arg(long = "screen-width")]
+    pub screen_width: u32,
+
+    /// rkvm2 config: The height of this node's screen in pixels.  Default 1080
+    #[arg(long = "screen-height")]
+    pub screen_height: u32,
+
+    /// rkvm2 config: The name of the node to the left of this one.  Moving the pointer off the edge switches to it
+    #[arg(long = "left")]
+    pub left: String,
+
+    /// rkvm2 config: The name of the node to the right of this one.  Moving the pointer off the edge switches to it
+    #[arg(long = "right")]
+    pub right: String,
+
+    /// rkvm2 config: The name of the node above this one.  Moving the pointer off the edge switches to it
+    #[arg(long = "above")]
+    pub above: String,
+
+    /// rkvm2 config: The name of the node below this one.  Moving the pointer off the edge switches to it
+    #[arg(long = "below")]
+    pub below: String,
+
+    /// rkvm2 config: The keys to use to switch to the node to the left of the active one.  Default LeftCtrl+LeftAlt+Left
+    #[arg(long = "switch-left-keys")]
+    pub left_keys: Vec<Key>,
+
+    /// rkvm2 config: The keys to use to switch to the node to the right of the active one.  Default LeftCtrl+LeftAlt+Right
+    #[arg(long = "switch-right-keys")]
+    pub right_keys: Vec<Key>,
+
+    /// rkvm2 config: The keys to use to switch to the node above the active one.  Default LeftCtrl+LeftAlt+Up
+    #[arg(long = "switch-up-keys")]
+    pub up_keys: Vec<Key>,
+
+    /// rkvm2 config: The keys to use to switch to the node below the active one.  Default LeftCtrl+LeftAlt+Down
+    #[arg(long = "switch-down-keys")]
+    pub down_keys: Vec<Key>,
+
+    /// rkvm2 config: True to wrap around to the node furthest the other way when switching in a direction with no node.  Default false
+    #[arg(short = 'w', long = "wrap-around")]
+    pub wrap_around: bool,
+
+    /// rkvm2 config: The order to switch through nodes, by name or id, with optional aliases.  Nodes not listed
+    /// come after, sorted by name.  Only read from the config file
+    #[arg(skip)]
+    pub nodes: Vec<NodeEntry>,
+
+    /// rkvm2 config: Extra key bindings, e.g. `RightCtrl+1: { switch_to: build-box }`.  Put spaces between chords
+    /// to press one after the other, e.g. `ScrollLock 2` or `RightCtrl RightCtrl`.  Only read from the config file
+    #[arg(skip)]
+    pub bindings: BTreeMap<String, Binding>,
+
+    /// rkvm2 config: Milliseconds to wait for the next chord of a binding sequence.  Default 1000
+    #[arg(long = "sequence-timeout")]
+    pub sequence_timeout: u64,
+
+    /// rkvm2 config: Milliseconds to wait for the next tap of a double tap binding.  Default 300
+    #[arg(long = "double-tap-timeout")]
+    pub double_tap_timeout: u64,
+
+    /// rkvm2 config: Named shell commands the commander may ask this node to run.  Messages aren't signed, so anyone
+    /// who can reach the broadcast address can run them.  Only read from the config file
+    #[arg(skip)]
+    pub commands: BTreeMap<String, String>,
+
+    /// rkvm2 config: How to show what happened, like who has the conn.  Only read from the config file
+    #[arg(skip)]
+    pub notifications: NotificationConfig,
+
+    /// rkvm2 config: True to send this node's desktop notifications to the commander while you're looking at
+    /// another node.  Default false
+    #[arg(long = "forward-notifications")]
+    pub forward_notifications: bool,
+
     /// rkvm2-inputd config: True if this host is the commander.  Default false
     #[arg(short = 'C', long = "commander")]
     pub commander: bool,
 
+    /// rkvm2 config: When several hosts are commanders, the reachable one with the highest priority is in charge.  Default 0
+    #[arg(short = 'P', long = "commander-priority")]
+    pub commander_priority: u32,
+
+    /// rkvm2 and rkvm2-inputd config: True to let any host become the commander by using its own keyboard or mouse.
+    /// Set it on every host.  Default false
+    #[arg(short = 'd', long = "dynamic-commander")]
+    pub dynamic_commander: bool,
+
     /// rkvm2-inputd config: The GID to use when creating the socket file on linux.  Default 0
     #[arg(short = 'g', long = "socket-gid")]
     pub socket_gid: u32,
 }
 
+/// A node in the configured node order
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NodeEntry {
+    /// The node's name or id
+    pub name: String,
+    /// What to call the node instead.  Works anywhere a node name does
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub alias: String,
+    /// Minutes without input before the commander takes the keyboard back from this node
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub idle_timeout: Option<u64>,
+    /// The name of one of the node's `commands` to run when it goes idle, like locking the screen
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub idle_command: String,
+}
+
+/// What to do when the keys of a binding are pressed
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Binding {
+    /// Switch to the node with this name (or id)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub switch_to: Option<String>,
+    /// Switch only the keyboard to the node with this name (or id)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub switch_keyboard_to: Option<String>,
+    /// Switch only the pointer and mouse buttons to the node with this name (or id)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub switch_pointer_to: Option<String>,
+    /// Switch only the scroll wheel to the node with this name (or id)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub switch_scroll_to: Option<String>,
+    /// Type into the nodes with these names (or ids) as well as the active node.  Press again to stop
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub mirror: Vec<String>,
+    /// Run this shell command here
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub run: Option<String>,
+    /// Ask the active node to run the command with this name from its `commands`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub run_on_active: Option<String>,
+}
+
+/// How to show notifications, with overrides for some kinds
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NotificationConfig {
+    /// For every kind
+    #[serde(flatten)]
+    pub defaults: NotificationStyle,
+    /// For some kinds, over the defaults.  Joins and leaves are off unless enabled.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub events: BTreeMap<NotificationEvent, NotificationStyle>,
+}
+impl NotificationConfig {
+    /// The style for a kind of notification, falling back to the defaults
+    pub fn style(&self, event: NotificationEvent) -> NotificationStyle {
+        let style = self.events.get(&event).cloned().unwrap_or_default();
+        NotificationStyle {
+            enabled: style.enabled.or(self.defaults.enabled),
+            summary: style.summary.or_else(|| self.defaults.summary.clone()),
+            body: style.body.or_else(|| self.defaults.body.clone()),
+            urgency: style.urgency.or(self.defaults.urgency),
+            timeout: style.timeout.or(self.defaults.timeout),
+            icon: style.icon.or_else(|| self.defaults.icon.clone()),
+        }
+    }
+}
+
+/// How to show a notification.  Anything left out comes from the defaults.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NotificationStyle {
+    /// False to not show it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub enabled: Option<bool>,
+    /// The title.  `{node}` is replaced with the name of the node it's about and `{message}` with what happened.
+    /// Default RKVM, or the node's name for notifications forwarded from other nodes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
+    /// The text, with the same replacements as the summary.  Default `{message}`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    /// low, normal or critical.  Default is up to the desktop
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub urgency: Option<Urgency>,
+    /// Milliseconds to show it for, or 0 to keep it until it's dismissed.  Default is up to the desktop
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u32>,
+    /// An icon name, like `input-keyboard`, or the path to an image
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+}
+
+/// The kinds of notification
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NotificationEvent {
+    /// The keyboard, pointer or scroll wheel moved to another node
+    Switch,
+    /// Another node took command
+    Commander,
+    /// Mirroring started or stopped
+    Mirror,
+    /// A node showed up
+    Join,
+    /// A node went quiet
+    Leave,
+    /// A notification from a node you're not looking at, or from rkvm2ctl
+    Forwarded,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Urgency {
+    Low,
+    Normal,
+    Critical,
+}
+
+/// Parse keys joined by `+`, like `RightCtrl+1`, into key codes.  Digits are the number row keys.
+/// Mouse buttons start with `Button`, like `ButtonSide`.
+pub fn parse_keys(keys: &str) -> Result<Vec<i32>, String> {
+    keys.split('+')
+        .map(str::trim)
+        .map(|key| {
+            let name = if key.len() == 1 && key.chars().all(|c| c.is_ascii_digit()) {
+                format!("N{}", key)
+            } else {
+                key.to_string()
+            };
+            if let Ok(key) = Key::from_str(&name) {
+                return Ok(key as i32);
+            }
+            name.strip_prefix("Button")
+                .and_then(|button| Button::from_str(button).ok())
+                .map(|button| button as i32)
+                .ok_or_else(|| format!("Unknown key {} in {}", key, keys))
+        })
+        .collect()
+}
+
+/// Parse chords separated by spaces, like `ScrollLock 2`
+pub fn parse_key_sequence(keys: &str) -> Result<Vec<Vec<i32>>, String> {
+    keys.split_whitespace()
+        .map(parse_keys)
+        .collect()
+}
+
 impl Config {
     pub fn read() -> Config {
         // Parse whole args with clap
@@ -82,6 +330,11 @@ impl Config {
         };
 
         // apply defaults
+        if config.name.is_empty() {
+            config.name = hostname::get()
+                .map(|h| h.to_string_lossy().to_string())
+                .unwrap_or_else(|_| "rkvm2".to_string());
+        }
         if config.broadcast_address.is_empty() {
             config.broadcast_address = "192.168.24.255:45321".to_string();
         }
@@ -93,12 +346,64 @@ impl Config {
             config.commander_keys.push(Key::RightCtrl);
             config.commander_keys.push(Key::Home);
         }
+        for (keys, arrow) in [
+            (&mut config.left_keys, Key::Left),
+            (&mut config.right_keys, Key::Right),
+            (&mut config.up_keys, Key::Up),
+            (&mut config.down_keys, Key::Down),
+        ] {
+            if keys.is_empty() {
+                keys.push(Key::LeftCtrl);
+                keys.push(Key::LeftAlt);
+                keys.push(arrow);
+            }
+        }
+        if config.sequence_timeout == 0 {
+            config.sequence_timeout = 1000;
+        }
+        if config.double_tap_timeout == 0 {
+            config.double_tap_timeout = 300;
+        }
+        if config.screen_width == 0 {
+            config.screen_width = 1920;
+        }
+        if config.screen_height == 0 {
+            config.screen_height = 1080;
+        }
 
         if args.dump_config {
             println!("# RKVM2 Config\n\n{}", serde_yaml::to_string(&config).expect("Failed to serialize config"));
             exit(0);
         }
 
-        return config;
+        config
+    }
+}
+
+/// Get the persistent id of this node, creating one in the config directory if needed.
+/// Unlike the hostname, this is unique even when two machines share a name.
+pub fn node_id() -> String {
+    let node_id_path = match ProjectDirs::from("com", "rkvm2", "rkvm2") {
+        None => {
+            log::warn!("No config directory.  Node id will change on restart");
+            return Uuid::new_v4().to_string();
+        }
+        Some(p) => p.config_dir().join(NODE_ID_FILE)
+    };
+
+    if let Ok(node_id) = fs::read_to_string(&node_id_path) {
+        if let Ok(node_id) = Uuid::parse_str(node_id.trim()) {
+            return node_id.to_string();
+        }
+        log::warn!("Invalid node id in {:?}.  Making a new one", node_id_path);
+    }
+
+    let node_id = Uuid::new_v4().to_string();
+    let written = node_id_path.parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| fs::write(&node_id_path, &node_id));
+    if let Err(e) = written {
+        log::warn!("Failed to save node id to {:?}.  Node id will change on restart.  {}", node_id_path, e);
     }
+    node_id
 }
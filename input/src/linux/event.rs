@@ -28,6 +28,8 @@ impl EvdevEventAdapter for InputEvent {
             InputEventType::Wheel(e) => (glue::EV_REL as _, glue::REL_WHEEL as _, e.delta),
             InputEventType::X(e) => (glue::EV_REL as _, glue::REL_X as _, e.delta),
             InputEventType::Y(e) => (glue::EV_REL as _, glue::REL_Y as _, e.delta),
+            // goes to a device of its own.  See EventWriter::warp
+            InputEventType::Position(_) => (glue::EV_SYN as _, glue::SYN_REPORT as _, 0),
         };
 
         input_event {
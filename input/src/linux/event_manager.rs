@@ -25,7 +25,8 @@ pub struct EventManager {
 }
 
 impl EventManager {
-    pub async fn new() -> Result<Self, Error> {
+    /// The screen size is the range of the positions we write
+    pub async fn new(screen_width: u32, screen_height: u32) -> Result<Self, Error> {
         let (event_sender, event_receiver) = mpsc::unbounded_channel();
 
         // HACK: When rkvm is run from the terminal, a race condition happens where the enter key
@@ -43,7 +44,7 @@ impl EventManager {
             spawn_reader(&entry.path(), event_sender.clone()).await?;
         }
 
-        let writer = EventWriter::new().await?;
+        let writer = EventWriter::new(screen_width, screen_height).await?;
 
         // Sleep for a while to give userspace time to register our devices.
         time::sleep(Duration::from_secs(1)).await;
@@ -1,58 +1,45 @@
+use std::ffi::c_void;
 use std::io::{Error, ErrorKind};
 use std::mem::MaybeUninit;
 use std::ops::RangeInclusive;
 
 use rkvm2_proto::InputEvent;
+use rkvm2_proto::input_event::InputEventType;
 
 use crate::linux::device_id;
-use crate::linux::glue::{self, __s32, __u16, input_event, libevdev, libevdev_uinput, timeval};
+use crate::linux::glue::{self, __s32, __u16, input_absinfo, input_event, libevdev, libevdev_uinput, timeval};
 
 pub struct EventWriter {
     evdev: *mut libevdev,
     uinput: *mut libevdev_uinput,
+    /// A screen sized tablet that only places the pointer.  Pointer acceleration would scale a
+    /// position written as relative motion, so it would land in the wrong place.
+    tablet_evdev: *mut libevdev,
+    tablet_uinput: *mut libevdev_uinput,
+    /// Largest position the tablet takes
+    tablet_max: (i32, i32),
 }
 
 impl EventWriter {
-    pub async fn new() -> Result<Self, Error> {
-        tokio::task::spawn_blocking(|| -> Result<Self, Error> {
-            return Self::new_sync();
-        })
-        .await?
+    pub async fn new(screen_width: u32, screen_height: u32) -> Result<Self, Error> {
+        tokio::task::spawn_blocking(move || Self::new_sync(screen_width, screen_height)).await?
     }
 
-    fn new_sync() -> Result<Self, Error> {
-        let evdev = unsafe { glue::libevdev_new() };
-        if evdev.is_null() {
-            return Err(Error::new(ErrorKind::Other, "Failed to create device"));
-        }
-
-        if let Err(err) = unsafe { setup_evdev(evdev) } {
-            unsafe {
-                glue::libevdev_free(evdev);
+    fn new_sync(screen_width: u32, screen_height: u32) -> Result<Self, Error> {
+        let tablet_max = (screen_width.max(1) as i32 - 1, screen_height.max(1) as i32 - 1);
+        let (evdev, uinput) = unsafe { create_device(|evdev| setup_evdev(evdev)) }?;
+        let (tablet_evdev, tablet_uinput) = match unsafe { create_device(|evdev| setup_tablet(evdev, tablet_max)) } {
+            Ok(tablet) => tablet,
+            Err(err) => {
+                unsafe {
+                    glue::libevdev_uinput_destroy(uinput);
+                    glue::libevdev_free(evdev);
+                }
+
+                return Err(err);
             }
-
-            return Err(err);
-        }
-
-        let mut uinput = MaybeUninit::uninit();
-        let ret = unsafe {
-            glue::libevdev_uinput_create_from_device(
-                evdev,
-                glue::libevdev_uinput_open_mode_LIBEVDEV_UINPUT_OPEN_MANAGED,
-                uinput.as_mut_ptr(),
-            )
         };
-
-        if ret < 0 {
-            unsafe { glue::libevdev_free(evdev) };
-            return Err(Error::new(
-                Error::from_raw_os_error(-ret).kind(),
-                format!("Failed to create from device ({})", ret),
-            ));
-        }
-
-        let uinput = unsafe { uinput.assume_init() };
-        let mut event_writer = Self { evdev, uinput };
+        let mut event_writer = Self { evdev, uinput, tablet_evdev, tablet_uinput, tablet_max };
 
         // send to 0, 0?
         event_writer.write_raw(input_event {
@@ -73,9 +60,21 @@ impl EventWriter {
     }
 
     pub async fn write(&mut self, event: InputEvent) -> Result<(), Error> {
+        if let Some(InputEventType::Position(position)) = &event.input_event_type {
+            return self.warp(position.x, position.y);
+        }
         self.write_raw(event.into())
     }
 
+    /// Place the pointer with the tablet
+    fn warp(&mut self, x: i32, y: i32) -> Result<(), Error> {
+        let uinput = self.tablet_uinput;
+        write_event(uinput, glue::EV_ABS as _, glue::ABS_X as _, x.clamp(0, self.tablet_max.0))?;
+        write_event(uinput, glue::EV_ABS as _, glue::ABS_Y as _, y.clamp(0, self.tablet_max.1))?;
+        write_event(uinput, glue::EV_SYN as _, glue::SYN_REPORT as _, 0)?;
+        Ok(())
+    }
+
     pub(crate) fn write_raw(&mut self, event: input_event) -> Result<(), Error> {
         self.write_raw_0(event.type_, event.code, event.value)?;
         self.write_raw_0(glue::EV_SYN as _, glue::SYN_REPORT as _, 0)?;
@@ -83,29 +82,15 @@ impl EventWriter {
     }
 
     fn write_raw_0(&mut self, r#type: __u16, code: __u16, value: __s32) -> Result<(), Error> {
-        // As far as tokio is concerned, the FD never becomes ready for writing, so just write it normally.
-        // If an error happens, it will be propagated to caller and the FD is opened in nonblocking mode anyway,
-        // so it shouldn't be an issue.
-
-        let ret = unsafe {
-            glue::libevdev_uinput_write_event(
-                self.uinput as *const _,
-                r#type as _,
-                code as _,
-                value,
-            )
-        };
-
-        if ret < 0 {
-            return Err(Error::from_raw_os_error(-ret));
-        }
-        Ok(())
+        write_event(self.uinput, r#type, code, value)
     }
 }
 
 impl Drop for EventWriter {
     fn drop(&mut self) {
         unsafe {
+            glue::libevdev_uinput_destroy(self.tablet_uinput);
+            glue::libevdev_free(self.tablet_evdev);
             glue::libevdev_uinput_destroy(self.uinput);
             glue::libevdev_free(self.evdev);
         }
@@ -114,6 +99,59 @@ impl Drop for EventWriter {
 
 unsafe impl Send for EventWriter {}
 
+fn write_event(uinput: *mut libevdev_uinput, r#type: __u16, code: __u16, value: __s32) -> Result<(), Error> {
+    // As far as tokio is concerned, the FD never becomes ready for writing, so just write it normally.
+    // If an error happens, it will be propagated to caller and the FD is opened in nonblocking mode anyway,
+    // so it shouldn't be an issue.
+
+    let ret = unsafe {
+        glue::libevdev_uinput_write_event(
+            uinput as *const _,
+            r#type as _,
+            code as _,
+            value,
+        )
+    };
+
+    if ret < 0 {
+        return Err(Error::from_raw_os_error(-ret));
+    }
+    Ok(())
+}
+
+/// Make a uinput device set up by the given function
+unsafe fn create_device(
+    setup: impl FnOnce(*mut libevdev) -> Result<(), Error>,
+) -> Result<(*mut libevdev, *mut libevdev_uinput), Error> {
+    let evdev = glue::libevdev_new();
+    if evdev.is_null() {
+        return Err(Error::new(ErrorKind::Other, "Failed to create device"));
+    }
+
+    if let Err(err) = setup(evdev) {
+        glue::libevdev_free(evdev);
+
+        return Err(err);
+    }
+
+    let mut uinput = MaybeUninit::uninit();
+    let ret = glue::libevdev_uinput_create_from_device(
+        evdev,
+        glue::libevdev_uinput_open_mode_LIBEVDEV_UINPUT_OPEN_MANAGED,
+        uinput.as_mut_ptr(),
+    );
+
+    if ret < 0 {
+        glue::libevdev_free(evdev);
+        return Err(Error::new(
+            Error::from_raw_os_error(-ret).kind(),
+            format!("Failed to create from device ({})", ret),
+        ));
+    }
+
+    Ok((evdev, uinput.assume_init()))
+}
+
 const TYPES: &[(u32, &[RangeInclusive<u32>])] = &[
     (glue::EV_SYN, &[glue::SYN_REPORT..=glue::SYN_REPORT]),
     (glue::EV_REL, &[0..=glue::REL_MAX]),
@@ -121,11 +159,7 @@ const TYPES: &[(u32, &[RangeInclusive<u32>])] = &[
 ];
 
 unsafe fn setup_evdev(evdev: *mut libevdev) -> Result<(), Error> {
-    glue::libevdev_set_name(evdev, b"rkvm2\0".as_ptr() as *const _);
-    glue::libevdev_set_id_vendor(evdev, device_id::VENDOR as _);
-    glue::libevdev_set_id_product(evdev, device_id::PRODUCT as _);
-    glue::libevdev_set_id_version(evdev, device_id::VERSION as _);
-    glue::libevdev_set_id_bustype(evdev, glue::BUS_USB as _);
+    set_id(evdev, b"rkvm2\0");
 
     for (r#type, codes) in TYPES.iter().copied() {
         let ret = glue::libevdev_enable_event_type(evdev, r#type);
@@ -137,18 +171,56 @@ unsafe fn setup_evdev(evdev: *mut libevdev) -> Result<(), Error> {
         }
 
         for code in codes.iter().cloned().flatten() {
-            let ret = glue::libevdev_enable_event_code(evdev, r#type, code, std::ptr::null_mut());
-            if ret < 0 {
-                return Err(Error::new(
-                    Error::from_raw_os_error(-ret).kind(),
-                    format!(
-                        "Failed to enable event type {} code {} ({})",
-                        r#type, code, ret
-                    ),
-                ));
-            }
+            enable_code(evdev, r#type, code, std::ptr::null())?;
         }
     }
 
     Ok(())
 }
+
+/// The tablet only has absolute axes the size of the screen, and buttons so it's taken for a
+/// pointer.  Its buttons are never pressed, they go to the other device.
+unsafe fn setup_tablet(evdev: *mut libevdev, (max_x, max_y): (i32, i32)) -> Result<(), Error> {
+    set_id(evdev, b"rkvm2 tablet\0");
+
+    enable_code(evdev, glue::EV_SYN, glue::SYN_REPORT, std::ptr::null())?;
+    for code in [glue::BTN_LEFT, glue::BTN_RIGHT, glue::BTN_MIDDLE] {
+        enable_code(evdev, glue::EV_KEY, code, std::ptr::null())?;
+    }
+    for (code, maximum) in [(glue::ABS_X, max_x), (glue::ABS_Y, max_y)] {
+        let info = input_absinfo {
+            value: 0,
+            minimum: 0,
+            maximum,
+            fuzz: 0,
+            flat: 0,
+            resolution: 0,
+        };
+        enable_code(evdev, glue::EV_ABS, code, &info as *const input_absinfo as *const _)?;
+    }
+
+    Ok(())
+}
+
+/// Name the device and give it our id so our reader leaves it alone
+unsafe fn set_id(evdev: *mut libevdev, name: &[u8]) {
+    glue::libevdev_set_name(evdev, name.as_ptr() as *const _);
+    glue::libevdev_set_id_vendor(evdev, device_id::VENDOR as _);
+    glue::libevdev_set_id_product(evdev, device_id::PRODUCT as _);
+    glue::libevdev_set_id_version(evdev, device_id::VERSION as _);
+    glue::libevdev_set_id_bustype(evdev, glue::BUS_USB as _);
+}
+
+unsafe fn enable_code(evdev: *mut libevdev, r#type: u32, code: u32, data: *const c_void) -> Result<(), Error> {
+    let ret = glue::libevdev_enable_event_code(evdev, r#type, code, data);
+    if ret < 0 {
+        return Err(Error::new(
+            Error::from_raw_os_error(-ret).kind(),
+            format!(
+                "Failed to enable event type {} code {} ({})",
+                r#type, code, ret
+            ),
+        ));
+    }
+    Ok(())
+}
@@ -20,12 +20,13 @@ async fn main() {
     let config = Config::read();
     log::debug!("Awaiting connection");
     let stream = pipe::accept(INPUT_PIPE_NAME, config.socket_gid).await;
-    handle_stream(stream, config.commander).await;
+    // with a dynamic commander, rkvm2 decides where local input goes
+    handle_stream(stream, config.commander || config.dynamic_commander, &config).await;
 }
 
-async fn handle_stream<T: AsyncRead + AsyncWrite>(stream: T, commander: bool) {
+async fn handle_stream<T: AsyncRead + AsyncWrite>(stream: T, commander: bool, config: &Config) {
     let (mut sink, mut source) = Framed::new(stream, MessageCodec::new()).split();
-    let mut event_manager = EventManager::new()
+    let mut event_manager = EventManager::new(config.screen_width, config.screen_height)
         .await
         .expect("Failed to create event manager");
     log::debug!("Received connection");
@@ -1,18 +1,29 @@
 extern crate core;
 
-use std::{fs, io};
-use std::fs::Permissions;
-use std::io::ErrorKind::AddrInUse;
-use std::os::unix::fs::PermissionsExt;
-use std::os::unix::net::UnixListener as StdUnixListener;
+use std::{env, fs, io};
+use std::fs::{DirBuilder, Permissions};
+use std::io::ErrorKind::{AddrInUse, AlreadyExists, NotFound};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::os::unix::net::{UnixListener as StdUnixListener, UnixStream as StdUnixStream};
+use std::path::Path;
 
-use nix::unistd::{chown, Gid};
+use nix::unistd::{chown, getuid, Gid};
 use tokio::net::{UnixListener, UnixStream};
 
 pub const INPUT_PIPE_NAME: &str = r"/var/run/rkvm2.sock";
 
 pub type ClientPipeStream = UnixStream;
 pub type ServerPipeStream = UnixStream;
+pub type PipeListener = UnixListener;
+
+/// The socket rkvm2ctl talks to rkvm2 on.  Both run as the user so it's in their runtime dir, or a
+/// directory of their own in /tmp.
+pub fn control_pipe_name() -> String {
+    match env::var("XDG_RUNTIME_DIR") {
+        Ok(dir) if !dir.is_empty() => format!("{}/rkvm2ctl.sock", dir),
+        _ => format!("/tmp/rkvm2-{}/rkvm2ctl.sock", getuid()),
+    }
+}
 
 pub async fn connect(name: &str) -> io::Result<ClientPipeStream> {
     UnixStream::connect(name).await
@@ -47,7 +58,7 @@ pub async fn accept(name: &str, gid: u32) -> ServerPipeStream {
             Err(e) => {
                 if e.kind() == AddrInUse {
                     fs::remove_file(name)
-                        .expect(format!("Failed to remove existing socket {}", e).as_str());
+                        .unwrap_or_else(|_| panic!("Failed to remove existing socket {}", e));
                 } else {
                     panic!("Failed to bind to socket {}", e);
                 }
@@ -55,3 +66,37 @@ pub async fn accept(name: &str, gid: u32) -> ServerPipeStream {
         }
     }
 }
+
+/// Listen for any number of connections from this user only.  The socket goes in a directory only
+/// this user can get into, so nobody else can connect before it's locked down.  Replaces a socket
+/// left behind by an earlier run, but not one that's still answering.
+pub fn listen(name: &str) -> io::Result<PipeListener> {
+    if let Some(dir) = Path::new(name).parent() {
+        private_dir(dir)?;
+    }
+    if StdUnixStream::connect(name).is_ok() {
+        return Err(io::Error::new(AddrInUse, format!("Something is already listening on {}", name)));
+    }
+    if let Err(e) = fs::remove_file(name) {
+        if e.kind() != NotFound {
+            return Err(e);
+        }
+    }
+    let listener = UnixListener::bind(name)?;
+    fs::set_permissions(name, Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// Make sure the directory exists and belongs to this user alone
+fn private_dir(dir: &Path) -> io::Result<()> {
+    match DirBuilder::new().mode(0o700).create(dir) {
+        Err(e) if e.kind() != AlreadyExists => return Err(e),
+        _ => {}
+    }
+    let metadata = fs::symlink_metadata(dir)?;
+    if !metadata.is_dir() || metadata.uid() != getuid().as_raw() || metadata.mode() & 0o077 != 0 {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                  format!("{:?} must be a directory only this user can use", dir)));
+    }
+    Ok(())
+}
@@ -7,6 +7,10 @@ pub const INPUT_PIPE_NAME: &str = r"\\.\rkvm2.pipe";
 pub type ClientPipeStream = NamedPipeClient;
 pub type ServerPipeStream = NamedPipeServer;
 
+pub fn control_pipe_name() -> String {
+    r"\\.\pipe\rkvm2ctl".to_string()
+}
+
 pub async fn connect(name: &str) -> io::Result<ClientPipeStream> {
 }
 
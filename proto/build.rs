@@ -35,6 +35,8 @@ fn main() -> Result<()> {
             "#[serde(default)]",
             prost_build::TypeSelector::ProtobufMessage,
         )
+        // PingEvent is much bigger than the rest, but it's sent once a second so boxing it isn't worth it
+        .type_attribute(".rkvm2.proto.Message.payload", "#[allow(clippy::large_enum_variant)]")
         .extern_path(".google.protobuf.Any", "::prost_wkt_types::Any")
         .extern_path(".google.protobuf.Timestamp", "::prost_wkt_types::Timestamp")
         .extern_path(".google.protobuf.Value", "::prost_wkt_types::Value")
@@ -52,28 +52,24 @@ impl<T: ProstMessage + Default> Decoder for MessageCodec<T> {
         let mut src = &mut self.buf;
 
         if (self.next_message_len == 0 && src.len() < HEADER_LEN) || (self.next_message_len > src.len()) {
-            return Ok(None);
+            Ok(None)
         } else {
             loop {
                 if self.next_message_len == 0 {
-                    match src.as_ref().chunks(MARKER_LEN).position(|c| c == &MARKER) {
-                        None => {
-                            return Ok(None)
-                        },
-                        Some(marker) => {
-                            if marker + HEADER_MESSAGE_LEN_LEN < src.len() {
-                                src.advance(marker + MARKER_LEN);
-                                self.next_message_len = src.get_u32() as usize;
-                            } else {
-                                return Ok(None);
-                            }
+                    match src.as_ref().chunks(MARKER_LEN).position(|c| c == MARKER) {
+                        Some(marker) if marker + HEADER_MESSAGE_LEN_LEN < src.len() => {
+                            src.advance(marker + MARKER_LEN);
+                            self.next_message_len = src.get_u32() as usize;
+                        }
+                        _ => {
+                            return Ok(None);
                         }
                     }
                 }
                 if self.next_message_len > src.len() {
                     return Ok(None);
                 }
-                let expected_remaining = src.remaining() - self.next_message_len as usize;
+                let expected_remaining = src.remaining() - self.next_message_len;
                 self.next_message_len = 0;
                 let mut message = T::default();
                 let result = encoding::message::merge(WireType::LengthDelimited, &mut message, &mut src, DecodeContext::default());
@@ -102,30 +98,48 @@ impl<T: ProstMessage> Encoder<T> for MessageCodec<T> {
         dst.put_slice(&MARKER);
         dst.put_u32(total_len as u32);
         item.encode_length_delimited(dst)?;
-        return Ok(());
+        Ok(())
     }
 }
 
 impl Header {
     /// Given the current time, how much time has elapsed since this message was generated?
     pub fn elapsed_time(&self, now: SystemTime) -> Option<Duration> {
+        self.elapsed_time_with_offset(now, 0)
+    }
+
+    /// Given the current time and how many nanoseconds the sender's clock is ahead of ours,
+    /// how much time has elapsed since this message was generated?
+    pub fn elapsed_time_with_offset(&self, now: SystemTime, clock_offset: i64) -> Option<Duration> {
         if let Some(time) = &self.time {
             if let Ok(time) = SystemTime::try_from(time.clone()) {
-                if let Ok(duration) = now.duration_since(time) {
+                // move the sender's time onto our clock
+                let time = if clock_offset >= 0 {
+                    time.checked_sub(Duration::from_nanos(clock_offset as u64))
+                } else {
+                    time.checked_add(Duration::from_nanos(clock_offset.unsigned_abs()))
+                };
+                if let Some(Ok(duration)) = time.map(|t| now.duration_since(t)) {
                     return Some(duration);
                 }
             }
         }
-        return None;
+        None
     }
 }
 
 impl Message {
     /// Given the current time, how much time has elapsed since this message was generated?
     pub fn elapsed_time(&self, now: SystemTime) -> Option<Duration> {
+        self.elapsed_time_with_offset(now, 0)
+    }
+
+    /// Given the current time and how many nanoseconds the sender's clock is ahead of ours,
+    /// how much time has elapsed since this message was generated?
+    pub fn elapsed_time_with_offset(&self, now: SystemTime, clock_offset: i64) -> Option<Duration> {
         match &self.header {
             None => None,
-            Some(h) => h.elapsed_time(now)
+            Some(h) => h.elapsed_time_with_offset(now, clock_offset)
         }
     }
 }
@@ -0,0 +1,210 @@
+use std::process::exit;
+
+use clap::{Parser, Subcommand};
+use futures::{SinkExt, StreamExt};
+use itertools::Itertools;
+use tokio_util::codec::Framed;
+
+use rkvm2_pipe::pipe;
+use rkvm2_proto::{ControlCommand, ControlRequest, Header, Message, MessageCodec, NodeStatus, RequestHeader, ResponseCode, StatusEvent};
+use rkvm2_proto::header::HeaderType;
+use rkvm2_proto::message::Payload;
+
+/// Control the rkvm2 running on this machine
+#[derive(Parser)]
+#[command(name = "rkvm2ctl", version)]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List the nodes, when they were last heard from and which one has the keyboard
+    List,
+    /// Switch the keyboard, pointer and scroll wheel to a node
+    Switch {
+        /// Name, alias or id of the node
+        node: String,
+    },
+    /// Switch to the next node, like the switch keys
+    Next,
+    /// Send this node's clipboard to the other nodes
+    SendClipboard,
+    /// Show where input goes and what each node is
+    Status {
+        #[arg(long)]
+        json: bool,
+    },
+    /// Show where input goes every time it changes, or the nodes come or go
+    Watch {
+        #[arg(long)]
+        json: bool,
+    },
+    /// Show a notification on whichever node you're looking at
+    Notify {
+        text: String,
+    },
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+    let (command, node, text) = match &args.command {
+        Command::List => (ControlCommand::Status, "", ""),
+        Command::Switch { node } => (ControlCommand::Switch, node.as_str(), ""),
+        Command::Next => (ControlCommand::Next, "", ""),
+        Command::SendClipboard => (ControlCommand::SendClipboard, "", ""),
+        Command::Status { .. } => (ControlCommand::Status, "", ""),
+        Command::Watch { .. } => (ControlCommand::Watch, "", ""),
+        Command::Notify { text } => (ControlCommand::Notify, "", text.as_str()),
+    };
+
+    let name = pipe::control_pipe_name();
+    let stream = match pipe::connect(&name).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            eprintln!("Failed to connect to {}.  Is rkvm2 running?  {}", name, e);
+            exit(1);
+        }
+    };
+    let (mut sink, mut stream) = Framed::new(stream, MessageCodec::new()).split();
+    let request = Message {
+        header: Some(Header {
+            id: "1".to_string(),
+            header_type: Some(HeaderType::Request(RequestHeader::default())),
+            ..Header::default()
+        }),
+        payload: Some(Payload::ControlRequest(ControlRequest {
+            command: command as i32,
+            node: node.to_string(),
+            text: text.to_string(),
+        })),
+    };
+    if let Err(e) = sink.send(request).await {
+        eprintln!("Failed to send request {}", e);
+        exit(1);
+    }
+
+    while let Some(response) = stream.next().await {
+        let (response, status) = match response {
+            Ok(Message {
+                header: Some(Header { header_type: Some(HeaderType::Response(response)), .. }),
+                payload: Some(Payload::StatusEvent(status)),
+            }) => (response, status),
+            Ok(message) => {
+                eprintln!("Ignoring unexpected message {:?}", message);
+                continue;
+            }
+            Err(e) => {
+                eprintln!("Failed to read response {}", e);
+                exit(1);
+            }
+        };
+        match response.code() {
+            ResponseCode::Error => {
+                eprintln!("{}", response.message);
+                exit(1);
+            }
+            ResponseCode::Ok => {
+                match &args.command {
+                    Command::List => print_nodes(&status),
+                    Command::Status { json: true } => print_json(&status, true),
+                    Command::Status { json: false } => print_status(&status),
+                    _ => {}
+                }
+                return;
+            }
+            ResponseCode::Update => {
+                match &args.command {
+                    Command::Watch { json: true } => print_json(&status, false),
+                    _ => println!("{}", summary(&status)),
+                }
+            }
+        }
+    }
+    eprintln!("rkvm2 hung up");
+    exit(1);
+}
+
+/// The name of the node with the given id
+fn name<'a>(status: &'a StatusEvent, id: &'a str) -> &'a str {
+    if id.is_empty() {
+        return "nobody";
+    }
+    status.nodes.iter()
+        .find(|n| n.id == id)
+        .map_or(id, |n| n.name.as_str())
+}
+
+/// When we last heard from the node and how good the link is
+fn liveness(node: &NodeStatus) -> String {
+    if node.local {
+        return "this node".to_string();
+    }
+    let mut liveness = format!("heard from {:.1}s ago", node.last_heard as f64 / 1000.0);
+    if node.rtt > 0.0 {
+        liveness += format!(", rtt {:.1}ms jitter {:.1}ms loss {:.0}%", node.rtt, node.jitter, node.loss * 100.0).as_str();
+    }
+    if node.slow {
+        liveness += " (slow)";
+    }
+    liveness
+}
+
+fn print_nodes(status: &StatusEvent) {
+    for node in &status.nodes {
+        let active = if node.id == status.active_node { "*" } else { " " };
+        println!("{} {:<20} {:<36} {}", active, node.name, node.id, liveness(node));
+    }
+}
+
+fn print_status(status: &StatusEvent) {
+    println!("commander: {}", name(status, &status.commander));
+    println!("keyboard:  {}", name(status, &status.active_node));
+    println!("pointer:   {}", name(status, &status.pointer_node));
+    println!("scroll:    {}", name(status, &status.scroll_node));
+    if !status.mirror_nodes.is_empty() {
+        println!("mirroring: {}", status.mirror_nodes.iter().map(|id| name(status, id)).join(", "));
+    }
+    println!();
+    for node in &status.nodes {
+        let info = node.info.clone().unwrap_or_default();
+        println!("{} ({})", node.name, node.id);
+        println!("  {}", liveness(node));
+        if !info.os.is_empty() {
+            println!("  rkvm2 {} on {}, up {}s", info.version, info.os, info.uptime);
+        }
+        if node.commander {
+            println!("  can be the commander");
+        }
+    }
+}
+
+fn print_json(status: &StatusEvent, pretty: bool) {
+    let json = if pretty {
+        serde_json::to_string_pretty(status)
+    } else {
+        serde_json::to_string(status)
+    };
+    match json {
+        Ok(json) => println!("{}", json),
+        Err(e) => {
+            eprintln!("Failed to serialize status {}", e);
+            exit(1);
+        }
+    }
+}
+
+/// One line about where input goes, for watching
+fn summary(status: &StatusEvent) -> String {
+    let mut summary = format!("keyboard {}, pointer {}, scroll {}",
+                              name(status, &status.active_node),
+                              name(status, &status.pointer_node),
+                              name(status, &status.scroll_node));
+    if !status.mirror_nodes.is_empty() {
+        summary += format!(", mirroring {}", status.mirror_nodes.iter().map(|id| name(status, id)).join(", ")).as_str();
+    }
+    summary += format!(", nodes {}", status.nodes.iter().map(|n| n.name.as_str()).join(", ")).as_str();
+    summary
+}
@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::ErrorKind;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use futures::{SinkExt, StreamExt};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio_util::codec::Framed;
+
+use rkvm2_pipe::pipe;
+use rkvm2_pipe::pipe::{PipeListener, ServerPipeStream};
+use rkvm2_proto::{ControlRequest, Header, Message, MessageCodec, RequestHeader};
+use rkvm2_proto::header::HeaderType;
+use rkvm2_proto::message::Payload;
+
+/// Request ids are prefixed with the client's number and this so the responses find their way back
+const CLIENT_SEPARATOR: char = '/';
+
+type Clients = Arc<Mutex<HashMap<u64, UnboundedSender<Message>>>>;
+
+/// Passes control requests to the app and its responses back to whoever asked, like rkvm2ctl on
+/// the control socket
+#[derive(Clone, Debug)]
+pub(crate) struct ControlServer {
+    sender: UnboundedSender<Message>,
+    clients: Clients,
+    next_client: Arc<AtomicU64>,
+}
+impl ControlServer {
+    /// Control requests go to the sender.  Send the app's responses to the returned sender.
+    pub(crate) fn open(sender: UnboundedSender<Message>) -> (Self, UnboundedSender<Message>) {
+        let server = Self {
+            sender,
+            clients: Clients::default(),
+            next_client: Arc::new(AtomicU64::new(1)),
+        };
+        let (response_sender, response_receiver) = unbounded_channel();
+        tokio::spawn(route(response_receiver, server.clone()));
+        (server, response_sender)
+    }
+
+    /// Serve rkvm2ctl on the control socket.  Fails if another rkvm2 is already serving it.
+    pub(crate) fn listen(&self) -> io::Result<()> {
+        let name = pipe::control_pipe_name();
+        match pipe::listen(&name) {
+            Ok(listener) => {
+                log::info!("Listening for rkvm2ctl on {}", name);
+                tokio::spawn(accept(listener, self.clone()));
+            }
+            Err(e) if e.kind() == ErrorKind::AddrInUse => {
+                return Err(e);
+            }
+            Err(e) => {
+                log::warn!("Failed to open the control socket {}.  rkvm2ctl won't work.  {}", name, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// A new client.  Its request ids only need to be unique to it.
+    pub(crate) fn connect(&self) -> ControlClient {
+        let id = self.next_client.fetch_add(1, Ordering::Relaxed);
+        let (client_sender, responses) = unbounded_channel();
+        self.clients.lock().unwrap().insert(id, client_sender);
+        ControlClient {
+            id,
+            server: self.clone(),
+            responses,
+        }
+    }
+
+    fn send(&self, request: Message) {
+        if let Err(e) = self.sender.send(request) {
+            log::warn!("Failed to send control request {}", e);
+        }
+    }
+}
+
+/// Someone asking the app for things.  Hangs up when dropped.
+#[derive(Debug)]
+pub(crate) struct ControlClient {
+    id: u64,
+    server: ControlServer,
+    /// Responses to this client's requests, with the request ids it gave
+    pub(crate) responses: UnboundedReceiver<Message>,
+}
+impl ControlClient {
+    pub(crate) fn send(&self, request_id: &str, header_type: Option<HeaderType>, request: ControlRequest) {
+        // it's local, so it mustn't look like it came from another node
+        self.server.send(Message {
+            header: Some(Header {
+                id: format!("{}{}{}", self.id, CLIENT_SEPARATOR, request_id),
+                header_type,
+                ..Header::default()
+            }),
+            payload: Some(Payload::ControlRequest(request)),
+        });
+    }
+}
+impl Drop for ControlClient {
+    fn drop(&mut self) {
+        self.server.clients.lock().unwrap().remove(&self.id);
+    }
+}
+
+async fn accept(listener: PipeListener, server: ControlServer) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _addr)) => {
+                tokio::spawn(serve(server.connect(), stream));
+            }
+            Err(e) => {
+                log::warn!("Accept failed {}", e);
+            }
+        }
+    }
+}
+
+/// Pass an rkvm2ctl's requests to the app and its responses back until it hangs up
+async fn serve(mut client: ControlClient, stream: ServerPipeStream) {
+    log::debug!("rkvm2ctl {} connected", client.id);
+    let (mut sink, mut stream) = Framed::new(stream, MessageCodec::new()).split();
+    loop {
+        tokio::select! {
+            request = stream.next() => {
+                match request {
+                    Some(Ok(Message { header, payload: Some(Payload::ControlRequest(request)) })) => {
+                        let header = header.unwrap_or_default();
+                        client.send(&header.id, header.header_type, request);
+                    }
+                    Some(Ok(message)) => {
+                        log::warn!("Ignoring control message that isn't a request {:?}", message);
+                    }
+                    Some(Err(e)) => {
+                        log::warn!("Failed to read control request {}", e);
+                        break;
+                    }
+                    None => {
+                        break;
+                    }
+                }
+            }
+            Some(response) = client.responses.recv() => {
+                if let Err(e) = sink.send(response).await {
+                    log::debug!("Failed to send control response {}", e);
+                    break;
+                }
+            }
+        }
+    }
+    log::debug!("rkvm2ctl {} disconnected", client.id);
+}
+
+/// Send responses to the client that asked.  If it's gone, cancel what it asked for.
+async fn route(mut responses: UnboundedReceiver<Message>, server: ControlServer) {
+    while let Some(mut response) = responses.recv().await {
+        let request_id = match &mut response.header {
+            Some(Header { header_type: Some(HeaderType::Response(response_header)), .. }) => &mut response_header.request_id,
+            _ => continue,
+        };
+        let full_request_id = request_id.clone();
+        let client = match full_request_id.split_once(CLIENT_SEPARATOR) {
+            Some((client, id)) => {
+                *request_id = id.to_string();
+                client.parse::<u64>().ok()
+            }
+            None => None,
+        };
+        let delivered = match client.and_then(|client| server.clients.lock().unwrap().get(&client).cloned()) {
+            Some(client_sender) => client_sender.send(response).is_ok(),
+            None => false,
+        };
+        if !delivered {
+            log::debug!("Cancelling control request {} for a client that's gone", full_request_id);
+            server.send(Message {
+                header: Some(Header {
+                    id: full_request_id,
+                    header_type: Some(HeaderType::Request(RequestHeader { cancel: true })),
+                    ..Header::default()
+                }),
+                payload: Some(Payload::ControlRequest(ControlRequest::default())),
+            });
+        }
+    }
+}
@@ -0,0 +1,258 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use futures::StreamExt;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::sync::oneshot;
+use zbus::{dbus_interface, fdo, Connection, ConnectionBuilder, MessageStream, MessageType, SignalContext};
+use zbus::zvariant::OwnedValue;
+
+use rkvm2_proto::{ControlCommand, ControlRequest, Header, Message, NotifyEvent, RequestHeader, ResponseCode, StatusEvent};
+use rkvm2_proto::header::HeaderType;
+use rkvm2_proto::message::Payload;
+
+use crate::control::ControlClient;
+use crate::desktop;
+
+pub(crate) const BUS_NAME: &str = "com.rkvm2.Rkvm2";
+pub(crate) const OBJECT_PATH: &str = "/com/rkvm2/Rkvm2";
+/// The id of the request keeping the status up to date
+const WATCH_ID: &str = "watch";
+const NOTIFICATIONS_INTERFACE: &str = "org.freedesktop.Notifications";
+
+/// The arguments of org.freedesktop.Notifications.Notify: app name, replaces id, icon, summary, body,
+/// actions, hints and timeout
+type Notify = (String, u32, String, String, String, Vec<String>, HashMap<String, OwnedValue>, i32);
+
+/// A node as shown on the bus: id, name and true for this node
+type BusNode = (String, String, bool);
+
+type Reply = oneshot::Sender<Result<(), String>>;
+
+/// rkvm2 on the session bus, for status bars and desktop tools
+pub(crate) struct DbusService;
+impl DbusService {
+    /// Serve on the session bus, giving up quietly if there isn't one
+    pub(crate) fn open(client: ControlClient) {
+        tokio::spawn(async move {
+            let builder = match ConnectionBuilder::session() {
+                Ok(builder) => builder,
+                Err(e) => {
+                    log::warn!("No session bus.  Desktop tools won't see rkvm2.  {}", e);
+                    return;
+                }
+            };
+            if let Err(e) = serve(builder, client).await {
+                log::warn!("Failed to serve {} on the session bus.  {}", BUS_NAME, e);
+            }
+        });
+    }
+}
+
+/// Watches the session bus for desktop notifications so nodes you aren't looking at can pass them on
+pub(crate) struct NotificationMonitor;
+impl NotificationMonitor {
+    /// Send the notifications shown on this desktop to the sender, giving up quietly if we can't see them
+    pub(crate) fn open(sender: UnboundedSender<Message>) {
+        tokio::spawn(async move {
+            let builder = match ConnectionBuilder::session() {
+                Ok(builder) => builder,
+                Err(e) => {
+                    log::warn!("No session bus.  Notifications won't be forwarded.  {}", e);
+                    return;
+                }
+            };
+            match become_monitor(builder).await {
+                Ok(connection) => forward_notifications(connection, sender).await,
+                Err(e) => log::warn!("Failed to watch for notifications.  They won't be forwarded.  {}", e),
+            }
+        });
+    }
+}
+
+/// Connect to the bus and ask to see every notification.  The connection can't send anything after.
+pub(crate) async fn become_monitor(builder: ConnectionBuilder<'_>) -> zbus::Result<Connection> {
+    let connection = builder.build().await?;
+    let rule = format!("type='method_call',interface='{}',member='Notify'", NOTIFICATIONS_INTERFACE);
+    connection.call_method(
+        Some("org.freedesktop.DBus"),
+        "/org/freedesktop/DBus",
+        Some("org.freedesktop.DBus.Monitoring"),
+        "BecomeMonitor",
+        &(vec![rule], 0u32),
+    ).await?;
+    log::info!("Watching the session bus for notifications");
+    Ok(connection)
+}
+
+/// Send the notifications seen by a monitor connection to the sender until the bus goes away
+pub(crate) async fn forward_notifications(connection: Connection, sender: UnboundedSender<Message>) {
+    let mut messages = MessageStream::from(&connection);
+    while let Some(message) = messages.next().await {
+        let message = match message {
+            Ok(message) => message,
+            Err(e) => {
+                log::debug!("Failed to read monitored message {}", e);
+                continue;
+            }
+        };
+        if message.message_type() != MessageType::MethodCall
+            || message.interface().as_ref().map(|i| i.as_str()) != Some(NOTIFICATIONS_INTERFACE)
+            || message.member().as_ref().map(|m| m.as_str()) != Some("Notify") {
+            continue;
+        }
+        let (app_name, _, _, summary, body, _, _, _) = match message.body::<Notify>() {
+            Ok(notify) => notify,
+            Err(e) => {
+                log::debug!("Failed to read notification {}", e);
+                continue;
+            }
+        };
+        // our own, or ones forwarded from other nodes
+        if app_name == desktop::APP_NAME {
+            continue;
+        }
+        let text = if body.is_empty() { summary } else { format!("{}\n{}", summary, body) };
+        if sender.send(Message {
+            header: None,
+            payload: Some(Payload::NotifyEvent(NotifyEvent { text })),
+        }).is_err() {
+            break;
+        }
+    }
+}
+
+/// Serve on the bus the builder connects to until the app goes away
+pub(crate) async fn serve(builder: ConnectionBuilder<'_>, mut client: ControlClient) -> zbus::Result<()> {
+    let (request_sender, mut requests) = unbounded_channel();
+    let status = Arc::new(Mutex::new(StatusEvent::default()));
+    let connection = builder
+        .name(BUS_NAME)?
+        .serve_at(OBJECT_PATH, Rkvm2Object { status: status.clone(), requests: request_sender })?
+        .build()
+        .await?;
+    log::info!("Serving {} on the session bus", BUS_NAME);
+
+    client.send(WATCH_ID, Some(HeaderType::Request(RequestHeader::default())), ControlRequest {
+        command: ControlCommand::Watch as i32,
+        ..ControlRequest::default()
+    });
+    let mut pending: HashMap<String, Reply> = HashMap::new();
+    let mut next_request = 0u64;
+    let mut watching = false;
+    loop {
+        tokio::select! {
+            Some((request, reply)) = requests.recv() => {
+                next_request += 1;
+                let id = next_request.to_string();
+                client.send(&id, Some(HeaderType::Request(RequestHeader::default())), request);
+                pending.insert(id, reply);
+            }
+            response = client.responses.recv() => {
+                let (response, new_status) = match response {
+                    Some(Message {
+                        header: Some(Header { header_type: Some(HeaderType::Response(response)), .. }),
+                        payload: Some(Payload::StatusEvent(status)),
+                    }) => (response, status),
+                    Some(_) => continue,
+                    None => break,
+                };
+                if response.request_id == WATCH_ID {
+                    let old_status = std::mem::replace(&mut *status.lock().unwrap(), new_status.clone());
+                    // the first status isn't news
+                    if watching {
+                        if let Err(e) = signal_changes(&connection, &old_status, &new_status).await {
+                            log::warn!("Failed to signal changes on the bus {}", e);
+                        }
+                    }
+                    watching = true;
+                } else if let Some(reply) = pending.remove(&response.request_id) {
+                    let result = match response.code() {
+                        ResponseCode::Error => Err(response.message),
+                        _ => Ok(()),
+                    };
+                    // nobody to tell if the caller gave up
+                    let _ = reply.send(result);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Tell the bus what changed between two statuses
+async fn signal_changes(connection: &Connection, old_status: &StatusEvent, new_status: &StatusEvent) -> zbus::Result<()> {
+    let object = connection.object_server().interface::<_, Rkvm2Object>(OBJECT_PATH).await?;
+    let ctxt = object.signal_context();
+    if old_status.active_node != new_status.active_node {
+        let name = bus_nodes(new_status).into_iter()
+            .find(|(id, _, _)| id == &new_status.active_node)
+            .map_or_else(String::new, |(_, name, _)| name);
+        Rkvm2Object::announce_active_node(ctxt, &new_status.active_node, &name).await?;
+        object.get().await.active_node_changed(ctxt).await?;
+    }
+    let nodes = bus_nodes(new_status);
+    if bus_nodes(old_status) != nodes {
+        Rkvm2Object::announce_nodes(ctxt, nodes).await?;
+        object.get().await.nodes_changed(ctxt).await?;
+    }
+    Ok(())
+}
+
+fn bus_nodes(status: &StatusEvent) -> Vec<BusNode> {
+    status.nodes.iter()
+        .map(|n| (n.id.clone(), n.name.clone(), n.local))
+        .collect()
+}
+
+struct Rkvm2Object {
+    /// As last heard from the app
+    status: Arc<Mutex<StatusEvent>>,
+    requests: UnboundedSender<(ControlRequest, Reply)>,
+}
+impl Rkvm2Object {
+    async fn ask(&self, command: ControlCommand, node: String) -> fdo::Result<()> {
+        let (reply, result) = oneshot::channel();
+        self.requests.send((ControlRequest { command: command as i32, node, ..ControlRequest::default() }, reply))
+            .map_err(|e| fdo::Error::Failed(e.to_string()))?;
+        result.await
+            .map_err(|e| fdo::Error::Failed(e.to_string()))?
+            .map_err(fdo::Error::Failed)
+    }
+}
+
+#[dbus_interface(name = "com.rkvm2.Rkvm2")]
+impl Rkvm2Object {
+    /// Switch the keyboard, pointer and scroll wheel to the node with the given name, alias or id
+    async fn switch(&self, node: String) -> fdo::Result<()> {
+        self.ask(ControlCommand::Switch, node).await
+    }
+
+    /// Switch to the next node, like the switch keys
+    async fn next(&self) -> fdo::Result<()> {
+        self.ask(ControlCommand::Next, String::new()).await
+    }
+
+    /// Send this node's clipboard to the other nodes
+    async fn send_clipboard(&self) -> fdo::Result<()> {
+        self.ask(ControlCommand::SendClipboard, String::new()).await
+    }
+
+    /// The known nodes in switching order
+    #[dbus_interface(property)]
+    fn nodes(&self) -> Vec<BusNode> {
+        bus_nodes(&self.status.lock().unwrap())
+    }
+
+    /// The id of the node with the keyboard, or empty if nobody has it
+    #[dbus_interface(property)]
+    fn active_node(&self) -> String {
+        self.status.lock().unwrap().active_node.clone()
+    }
+
+    #[dbus_interface(signal, name = "ActiveNodeChanged")]
+    async fn announce_active_node(ctxt: &SignalContext<'_>, id: &str, name: &str) -> zbus::Result<()>;
+
+    #[dbus_interface(signal, name = "NodesChanged")]
+    async fn announce_nodes(ctxt: &SignalContext<'_>, nodes: Vec<BusNode>) -> zbus::Result<()>;
+}
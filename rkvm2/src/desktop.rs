@@ -0,0 +1,66 @@
+use arboard::Clipboard;
+use notify_rust::{Notification, NotificationHandle, Timeout};
+#[cfg(all(unix, not(target_os = "macos")))]
+use notify_rust::Urgency;
+
+use rkvm2_app::desktop;
+
+/// The system clipboard.  Opened fresh each time since it may not be there when we start.
+#[derive(Debug)]
+pub(crate) struct DesktopClipboard;
+impl desktop::Clipboard for DesktopClipboard {
+    fn get_text(&mut self) -> Result<String, String> {
+        let mut clipboard = Clipboard::new().map_err(|e| format!("Failed to get clipboard {}", e))?;
+        clipboard.get_text().map_err(|e| e.to_string())
+    }
+
+    fn set_text(&mut self, text: String) -> Result<(), String> {
+        let mut clipboard = Clipboard::new().map_err(|e| format!("Failed to get clipboard {}", e))?;
+        clipboard.set_text(text).map_err(|e| e.to_string())
+    }
+}
+
+/// The app name on our own notifications, so we don't forward them
+pub(crate) const APP_NAME: &str = "rkvm2";
+
+/// Desktop notifications.  Each one replaces the last, except ones forwarded from other nodes.
+#[derive(Default)]
+pub(crate) struct DesktopNotifier {
+    current_notification: Option<NotificationHandle>,
+}
+impl desktop::Notifier for DesktopNotifier {
+    fn notify(&mut self, notification: desktop::Notification) {
+        let mut shown = Notification::new();
+        shown.appname(APP_NAME)
+            .summary(&notification.summary)
+            .body(&notification.body);
+        if !notification.icon.is_empty() {
+            shown.icon(&notification.icon);
+        }
+        if let Some(timeout) = notification.timeout {
+            shown.timeout(if timeout == 0 { Timeout::Never } else { Timeout::Milliseconds(timeout) });
+        }
+        // only freedesktop notifications have an urgency
+        #[cfg(all(unix, not(target_os = "macos")))]
+        if let Some(urgency) = notification.urgency {
+            shown.urgency(match urgency {
+                rkvm2_config::Urgency::Low => Urgency::Low,
+                rkvm2_config::Urgency::Normal => Urgency::Normal,
+                rkvm2_config::Urgency::Critical => Urgency::Critical,
+            });
+        }
+        match shown.show() {
+            Ok(notification_handle) => {
+                if !notification.replace {
+                    return;
+                }
+                if let Some(previous_handle) = self.current_notification.replace(notification_handle) {
+                    previous_handle.close();
+                }
+            }
+            Err(e) => {
+                log::debug!("Failed to notify {}", e);
+            }
+        }
+    }
+}
@@ -12,7 +12,7 @@ use rkvm2_pipe::pipe;
 use rkvm2_pipe::pipe::{ClientPipeStream, INPUT_PIPE_NAME};
 use rkvm2_proto::{Message, MessageCodec};
 
-use crate::conn::{Connection, Connector, MessageSink, MessageStream};
+use rkvm2_app::conn::{Connection, Connector, MessageSink, MessageStream};
 
 pub struct StreamSink<T: AsyncRead + AsyncWrite + Send> {
     sink: SplitSink<Framed<T, MessageCodec<Message>>, Message>,
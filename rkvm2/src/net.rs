@@ -13,7 +13,7 @@ use tokio_util::udp::UdpFramed;
 
 use rkvm2_proto::{Message, MessageCodec};
 
-use crate::conn::{Connection, Connector, MessageSink, MessageStream};
+use rkvm2_app::conn::{Connection, Connector, MessageSink, MessageStream};
 
 pub struct UdpSink {
     sink: SplitSink<UdpFramed<MessageCodec<Message>>, (Message, SocketAddr)>,
@@ -31,10 +31,16 @@ pub struct UdpStream {
 #[async_trait]
 impl MessageStream for UdpStream {
     async fn next(&mut self) -> Option<Result<Message, Error>> {
-        match self.stream.next().await {
-            None => None,
-            Some(Ok((message, _))) => Some(Ok(message)),
-            Some(Err(e)) => Some(Err(e)),
+        loop {
+            match self.stream.next().await {
+                None => return None,
+                // the app takes messages that don't say who they're from for its own, like rkvm2ctl's
+                Some(Ok((message, address))) if message.header.as_ref().is_none_or(|h| h.from_id.is_empty()) => {
+                    log::debug!("Dropping a message from {} that doesn't say who it's from", address);
+                }
+                Some(Ok((message, _))) => return Some(Ok(message)),
+                Some(Err(e)) => return Some(Err(e)),
+            }
         }
     }
 }
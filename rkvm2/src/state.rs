@@ -0,0 +1,59 @@
+use std::fs;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+
+use directories::ProjectDirs;
+
+use rkvm2_app::state::{SavedState, StateStore};
+
+const STATE_FILE: &str = "state.yml";
+
+/// Keeps the state in the state directory, like ~/.local/state/rkvm2/state.yml
+#[derive(Debug)]
+pub(crate) struct FileStateStore {
+    path: Option<PathBuf>,
+}
+impl FileStateStore {
+    pub(crate) fn new() -> Self {
+        let path = ProjectDirs::from("com", "rkvm2", "rkvm2")
+            .map(|p| p.state_dir().unwrap_or_else(|| p.data_local_dir()).join(STATE_FILE));
+        if path.is_none() {
+            log::warn!("No state directory.  The active node will be forgotten on restart");
+        }
+        Self { path }
+    }
+}
+impl StateStore for FileStateStore {
+    fn load(&mut self) -> Option<SavedState> {
+        let path = self.path.as_ref()?;
+        let file = File::open(path).ok()?;
+        match serde_yaml::from_reader(BufReader::new(file)) {
+            Ok(state) => Some(state),
+            Err(e) => {
+                log::warn!("Ignoring bad state in {:?}.  {}", path, e);
+                None
+            }
+        }
+    }
+
+    fn save(&mut self, state: &SavedState) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        let state = match serde_yaml::to_string(state) {
+            Ok(state) => state,
+            Err(e) => {
+                log::warn!("Failed to serialize state {}", e);
+                return;
+            }
+        };
+        let written = path.parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::write(path, state));
+        if let Err(e) = written {
+            log::warn!("Failed to save state to {:?}.  {}", path, e);
+        }
+    }
+}
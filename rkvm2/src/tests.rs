@@ -0,0 +1,253 @@
+//! Serves the D-Bus object on a private bus with a stand-in for the app, and listens on a local socket
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader};
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::time::{sleep, timeout};
+use tokio_util::udp::UdpFramed;
+use zbus::{dbus_interface, dbus_proxy, CacheProperties, ConnectionBuilder};
+use zbus::zvariant::Value;
+
+use rkvm2_proto::{ControlCommand, ControlRequest, Header, Message, MessageCodec, NodeStatus, NotifyEvent, PingEvent, ResponseCode, ResponseHeader, StatusEvent};
+use rkvm2_proto::header::HeaderType;
+use rkvm2_proto::message::Payload;
+
+use crate::control::ControlServer;
+use crate::dbus;
+use crate::desktop;
+use crate::net::Distributor;
+
+#[dbus_proxy(interface = "com.rkvm2.Rkvm2", default_service = "com.rkvm2.Rkvm2", default_path = "/com/rkvm2/Rkvm2")]
+trait Rkvm2 {
+    fn switch(&self, node: &str) -> zbus::Result<()>;
+
+    #[dbus_proxy(property)]
+    fn nodes(&self) -> zbus::Result<Vec<(String, String, bool)>>;
+
+    #[dbus_proxy(property)]
+    fn active_node(&self) -> zbus::Result<String>;
+
+    #[dbus_proxy(signal, name = "ActiveNodeChanged")]
+    fn active_node_announced(&self, id: &str, name: &str) -> zbus::Result<()>;
+
+    #[dbus_proxy(signal, name = "NodesChanged")]
+    fn nodes_announced(&self, nodes: Vec<(String, String, bool)>) -> zbus::Result<()>;
+}
+
+#[dbus_proxy(interface = "org.freedesktop.Notifications", default_service = "org.freedesktop.Notifications", default_path = "/org/freedesktop/Notifications")]
+trait Notifications {
+    #[allow(clippy::too_many_arguments)]
+    fn notify(&self, app_name: &str, replaces_id: u32, app_icon: &str, summary: &str, body: &str, actions: &[&str],
+              hints: HashMap<&str, &Value<'_>>, expire_timeout: i32) -> zbus::Result<u32>;
+}
+
+/// Stands in for the desktop's notification daemon
+struct FakeNotificationDaemon;
+#[dbus_interface(name = "org.freedesktop.Notifications")]
+impl FakeNotificationDaemon {
+    #[allow(clippy::too_many_arguments)]
+    fn notify(&self, _app_name: String, _replaces_id: u32, _app_icon: String, _summary: String, _body: String,
+              _actions: Vec<String>, _hints: HashMap<String, zbus::zvariant::OwnedValue>, _expire_timeout: i32) -> u32 {
+        1
+    }
+}
+
+/// A dbus-daemon of our own, stopped when dropped.  The tests that need one fail without it
+/// rather than pass without testing anything.
+struct PrivateBus {
+    daemon: Child,
+    address: String,
+}
+impl PrivateBus {
+    fn start() -> Self {
+        let mut daemon = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("These tests need dbus-daemon on the PATH");
+        let mut address = String::new();
+        BufReader::new(daemon.stdout.take().unwrap()).read_line(&mut address).unwrap();
+        Self {
+            daemon,
+            address: address.trim().to_string(),
+        }
+    }
+
+    fn connection(&self) -> ConnectionBuilder<'static> {
+        ConnectionBuilder::address(self.address.as_str()).unwrap()
+    }
+}
+impl Drop for PrivateBus {
+    fn drop(&mut self) {
+        let _ = self.daemon.kill();
+        let _ = self.daemon.wait();
+    }
+}
+
+/// Stands in for the app at the other end of the control server
+struct FakeApp {
+    requests: UnboundedReceiver<Message>,
+    responses: UnboundedSender<Message>,
+    status: StatusEvent,
+}
+impl FakeApp {
+    async fn request(&mut self) -> (String, ControlRequest) {
+        match timeout(Duration::from_secs(5), self.requests.recv()).await {
+            Ok(Some(Message { header: Some(header), payload: Some(Payload::ControlRequest(request)) })) => (header.id, request),
+            other => panic!("Expected a control request, got {:?}", other),
+        }
+    }
+
+    fn respond(&self, request_id: &str, code: ResponseCode, message: &str) {
+        self.responses.send(Message {
+            header: Some(Header {
+                header_type: Some(HeaderType::Response(ResponseHeader {
+                    code: code as i32,
+                    message: message.to_string(),
+                    request_id: request_id.to_string(),
+                })),
+                ..Header::default()
+            }),
+            payload: Some(Payload::StatusEvent(self.status.clone())),
+        }).unwrap();
+    }
+}
+
+fn node(id: &str, name: &str, local: bool) -> NodeStatus {
+    NodeStatus {
+        id: id.to_string(),
+        name: name.to_string(),
+        local,
+        ..NodeStatus::default()
+    }
+}
+
+fn bus_node(id: &str, name: &str, local: bool) -> (String, String, bool) {
+    (id.to_string(), name.to_string(), local)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn desktop_tools_see_and_switch_the_active_node() {
+    let bus = PrivateBus::start();
+    let (app_sender, requests) = unbounded_channel();
+    let (control, responses) = ControlServer::open(app_sender);
+    let mut app = FakeApp {
+        requests,
+        responses,
+        status: StatusEvent {
+            id: "a".to_string(),
+            active_node: "a".to_string(),
+            nodes: vec![node("a", "alpha", true), node("b", "beta", false)],
+            ..StatusEvent::default()
+        },
+    };
+    tokio::spawn(dbus::serve(bus.connection(), control.connect()));
+
+    // the service is on the bus once it starts watching
+    let (watch_id, watch) = app.request().await;
+    assert_eq!(ControlCommand::Watch, watch.command());
+    app.respond(&watch_id, ResponseCode::Update, "");
+
+    let connection = bus.connection().build().await.unwrap();
+    let proxy = Rkvm2Proxy::builder(&connection)
+        .cache_properties(CacheProperties::No)
+        .build()
+        .await
+        .unwrap();
+    let mut active_node_changes = proxy.receive_active_node_announced().await.unwrap();
+    let mut node_changes = proxy.receive_nodes_announced().await.unwrap();
+    assert_eq!(vec![bus_node("a", "alpha", true), bus_node("b", "beta", false)], proxy.nodes().await.unwrap());
+    assert_eq!("a", proxy.active_node().await.unwrap());
+
+    let switch = tokio::spawn(async move {
+        let result = proxy.switch("beta").await;
+        (proxy, result)
+    });
+    let (switch_id, request) = app.request().await;
+    assert_eq!((ControlCommand::Switch, "beta"), (request.command(), request.node.as_str()));
+    app.status.active_node = "b".to_string();
+    app.respond(&watch_id, ResponseCode::Update, "");
+    app.respond(&switch_id, ResponseCode::Ok, "");
+    let (proxy, result) = switch.await.unwrap();
+    result.unwrap();
+    let changed = timeout(Duration::from_secs(5), active_node_changes.next()).await.unwrap().unwrap();
+    let args = changed.args().unwrap();
+    assert_eq!(("b", "beta"), (*args.id(), *args.name()));
+    assert_eq!("b", proxy.active_node().await.unwrap());
+
+    let switch = tokio::spawn(async move { proxy.switch("nowhere").await });
+    let (switch_id, _) = app.request().await;
+    app.respond(&switch_id, ResponseCode::Error, "No node called nowhere");
+    let error = switch.await.unwrap().unwrap_err();
+    assert!(error.to_string().contains("No node called nowhere"), "{}", error);
+
+    app.status.nodes.push(node("c", "gamma", false));
+    app.respond(&watch_id, ResponseCode::Update, "");
+    let changed = timeout(Duration::from_secs(5), node_changes.next()).await.unwrap().unwrap();
+    assert_eq!(3, changed.args().unwrap().nodes().len());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn desktop_notifications_are_forwarded() {
+    let bus = PrivateBus::start();
+    let _daemon = bus.connection()
+        .name("org.freedesktop.Notifications").unwrap()
+        .serve_at("/org/freedesktop/Notifications", FakeNotificationDaemon).unwrap()
+        .build()
+        .await
+        .unwrap();
+    let (sender, mut notifications) = unbounded_channel();
+    let monitor = dbus::become_monitor(bus.connection()).await.unwrap();
+    tokio::spawn(dbus::forward_notifications(monitor, sender));
+
+    let connection = bus.connection().build().await.unwrap();
+    let proxy = NotificationsProxy::new(&connection).await.unwrap();
+    // ours, or forwarded from another node
+    proxy.notify(desktop::APP_NAME, 0, "", "beta", "Build done", &[], HashMap::new(), -1).await.unwrap();
+    proxy.notify("make", 0, "", "Build done", "All green", &[], HashMap::new(), -1).await.unwrap();
+    proxy.notify("make", 0, "", "Tests passed", "", &[], HashMap::new(), -1).await.unwrap();
+
+    let mut texts = Vec::new();
+    for _ in 0..2 {
+        match timeout(Duration::from_secs(5), notifications.recv()).await {
+            Ok(Some(Message { header: None, payload: Some(Payload::NotifyEvent(NotifyEvent { text })) })) => texts.push(text),
+            other => panic!("Expected a notification, got {:?}", other),
+        }
+    }
+    assert_eq!(vec!["Build done\nAll green", "Tests passed"], texts);
+}
+
+#[tokio::test]
+async fn the_network_cannot_pass_for_the_app() {
+    // somewhere free to listen
+    let address = std::net::UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let (app_sender, mut app) = unbounded_channel();
+    let _net = Distributor::open(address.to_string(), app_sender);
+    sleep(Duration::from_millis(100)).await;
+
+    let mut socket = UdpFramed::new(UdpSocket::bind("127.0.0.1:0").await.unwrap(), MessageCodec::new());
+    // no from_id makes it look like rkvm2ctl
+    socket.send((Message {
+        header: Some(Header::default()),
+        payload: Some(Payload::ControlRequest(ControlRequest {
+            command: ControlCommand::SendClipboard as i32,
+            ..ControlRequest::default()
+        })),
+    }, address)).await.unwrap();
+    socket.send((Message {
+        header: Some(Header {
+            from_id: "b".to_string(),
+            ..Header::default()
+        }),
+        payload: Some(Payload::PingEvent(PingEvent::default())),
+    }, address)).await.unwrap();
+
+    let message = timeout(Duration::from_secs(5), app.recv()).await.unwrap().unwrap();
+    assert!(matches!(message.payload, Some(Payload::PingEvent(_))));
+    assert!(app.try_recv().is_err());
+}